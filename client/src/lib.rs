@@ -0,0 +1,9 @@
+/*
+ * Copyright 2024 Oxide Computer Company
+ */
+
+/*
+ * A client for the HTTP API of the office clock, generated from the OpenAPI
+ * document checked in at "openapi/clock.json".
+ */
+progenitor::generate_api!("../openapi/clock.json");
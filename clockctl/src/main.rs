@@ -0,0 +1,368 @@
+/*
+ * Copyright 2024 Oxide Computer Company
+ */
+
+use std::{io::Read, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand};
+use clock_client::{types, Client};
+use futures::TryStreamExt;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use tokio::io::AsyncWriteExt;
+
+#[derive(Parser)]
+#[command(about = "control the office clock")]
+struct Args {
+    /// The base URL of the clock's HTTP API
+    #[arg(
+        short,
+        long,
+        env = "CLOCK_URL",
+        default_value = "http://localhost:8888"
+    )]
+    url: String,
+
+    /// A token for the clock's API, if it requires one
+    #[arg(short = 'T', long, env = "CLOCK_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// A PEM file containing an additional CA certificate to trust, e.g.,
+    /// for a clock using a self-signed certificate
+    #[arg(long, env = "CLOCK_CA_CERT")]
+    ca_cert: Option<PathBuf>,
+
+    #[command(subcommand)]
+    cmd: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Display a message; the text is read from stdin if not provided
+    Message {
+        text: Option<String>,
+        /// Colour, as hex ("#48d597") or a name ("red")
+        #[arg(short, long, default_value = "white", value_parser = colour)]
+        colour: [u8; 3],
+        /// Height of each line of text in pixels; if not specified, the
+        /// message is made as large as will fit on the display
+        #[arg(short = 'H', long, conflicts_with = "max_height")]
+        height: Option<u32>,
+        /// The largest height to use when fitting the message to the display
+        #[arg(short, long)]
+        max_height: Option<u32>,
+        /// Flash the message on and off with this period (e.g., "500ms")
+        #[arg(short, long, value_parser = duration)]
+        flash: Option<Duration>,
+        /// Shrink the text if it will not fit on the display
+        #[arg(long)]
+        fit: bool,
+        /// Scroll the message across the whole display
+        #[arg(short, long, conflicts_with = "ticker")]
+        scroll: bool,
+        /// Scroll the message along the bottom of the display, beneath the
+        /// time
+        #[arg(long)]
+        ticker: bool,
+        /// Scrolling speed, in pixels per second
+        #[arg(long)]
+        speed: Option<u32>,
+        /// Remove the message again after this long (e.g., "10m")
+        #[arg(short, long, value_parser = duration)]
+        ttl: Option<Duration>,
+    },
+    /// Display an image file
+    Image {
+        file: PathBuf,
+        /// Draw the clock on top of the image, across the whole display
+        /// ("full") or in one half of it ("left" or "right")
+        #[arg(short, long, value_parser = choice::<types::ClockPosition>)]
+        clock: Option<types::ClockPosition>,
+        /// Where to put the image, if it does not fill the display ("left",
+        /// "centre", or "right")
+        #[arg(short, long, value_parser = choice::<types::ImageAlign>)]
+        align: Option<types::ImageAlign>,
+        /// Scale the image to cover the whole display, cropping if need be
+        #[arg(long)]
+        cover: bool,
+        /// How to keep the clock legible against the image ("plain",
+        /// "shadow", or "outline")
+        #[arg(short, long, value_parser = choice::<types::Legibility>)]
+        legibility: Option<types::Legibility>,
+        /// Remove the image again after this long (e.g., "10m")
+        #[arg(short, long, value_parser = duration)]
+        ttl: Option<Duration>,
+    },
+    /// Add a font (TrueType or OpenType) for use in messages
+    Font {
+        file: PathBuf,
+        /// A name for the font; adding another font with the same name
+        /// replaces it (defaults to the file name)
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Code points for which to prefer this font, as a comma-separated
+        /// list of hexadecimal code points or ranges (e.g., "0590-05FF")
+        #[arg(short, long)]
+        ranges: Option<String>,
+    },
+    /// Turn the display "on" or "off" regardless of the power schedule, or
+    /// return to following the schedule ("auto")
+    Power {
+        #[arg(value_parser = choice::<types::PowerMode>)]
+        mode: types::PowerMode,
+        /// Return to the schedule after this long (e.g., "2h")
+        #[arg(short, long, value_parser = duration)]
+        ttl: Option<Duration>,
+    },
+    /// Remove any message, image, or timer and display the clock
+    Clear,
+    /// Print a description of what is currently displayed
+    State,
+    /// Save a PNG file containing the current contents of the display
+    Screenshot {
+        #[arg(default_value = "clock.png")]
+        file: PathBuf,
+    },
+    /// Count down for the specified time (e.g., "5m", "1h30m", "90")
+    Timer {
+        #[arg(value_parser = duration)]
+        time: Duration,
+        /// A label to display below the countdown
+        #[arg(short, long)]
+        label: Option<String>,
+        /// Colour, as hex ("#48d597") or a name ("red")
+        #[arg(short, long, value_parser = colour)]
+        colour: Option<[u8; 3]>,
+        /// Remove the timer again after this long (e.g., "10m")
+        #[arg(short, long, value_parser = duration)]
+        ttl: Option<Duration>,
+    },
+}
+
+const COLOURS: &[(&str, [u8; 3])] = &[
+    ("white", [0xff, 0xff, 0xff]),
+    ("black", [0x00, 0x00, 0x00]),
+    ("grey", [0x7d, 0x83, 0x85]),
+    ("gray", [0x7d, 0x83, 0x85]),
+    ("red", [0xff, 0x00, 0x00]),
+    ("orange", [0xff, 0xa5, 0x00]),
+    ("yellow", [0xff, 0xff, 0x00]),
+    ("green", [0x00, 0xff, 0x00]),
+    ("oxide", [0x48, 0xd5, 0x97]),
+    ("blue", [0x00, 0x00, 0xff]),
+    ("cyan", [0x00, 0xff, 0xff]),
+    ("magenta", [0xff, 0x00, 0xff]),
+    ("purple", [0x80, 0x00, 0x80]),
+    ("pink", [0xff, 0xc0, 0xcb]),
+];
+
+fn colour(s: &str) -> Result<[u8; 3]> {
+    let s = s.trim().to_ascii_lowercase();
+
+    if let Some((_, rgb)) = COLOURS.iter().find(|(name, _)| *name == s) {
+        return Ok(*rgb);
+    }
+
+    let hex = s.strip_prefix('#').unwrap_or(&s);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("{s:?} is neither a colour name nor a hex triplet like #48d597");
+    }
+
+    let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok([byte(0), byte(2), byte(4)])
+}
+
+/*
+ * Parse a duration, like "90" (seconds), "90s", "1h30m", or "500ms".
+ */
+fn duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let bad = || anyhow!("invalid duration {s:?}");
+
+    if let Some(ms) = s.strip_suffix("ms") {
+        return Ok(Duration::from_millis(ms.parse().map_err(|_| bad())?));
+    }
+
+    if let Ok(secs) = s.parse() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut num = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+
+        let mult = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(bad()),
+        };
+        let n: u64 = num.parse().map_err(|_| bad())?;
+        total += Duration::from_secs(n.checked_mul(mult).ok_or_else(bad)?);
+        num.clear();
+    }
+
+    if !num.is_empty() {
+        bail!("invalid duration {s:?}: trailing number without a unit");
+    }
+
+    Ok(total)
+}
+
+/*
+ * Parse one of the values of an enumerated type in the API.
+ */
+fn choice<T: std::str::FromStr>(s: &str) -> Result<T> {
+    s.parse().map_err(|_| anyhow!("invalid value {s:?}"))
+}
+
+fn secs(d: Duration) -> Result<u32> {
+    d.as_secs().try_into().map_err(|_| anyhow!("duration {d:?} too long"))
+}
+
+fn msecs(d: Duration) -> Result<u32> {
+    d.as_millis().try_into().map_err(|_| anyhow!("duration {d:?} too long"))
+}
+
+fn client(args: &Args) -> Result<Client> {
+    let mut headers = HeaderMap::new();
+    if let Some(token) = args.token.as_deref() {
+        let mut v = HeaderValue::from_str(&format!("Bearer {token}"))?;
+        v.set_sensitive(true);
+        headers.insert(AUTHORIZATION, v);
+    }
+
+    let mut client = reqwest::ClientBuilder::new().default_headers(headers);
+
+    if let Some(path) = args.ca_cert.as_deref() {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("reading CA certificate {path:?}"))?;
+        client = client.add_root_certificate(
+            reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("parsing {path:?}"))?,
+        );
+    }
+
+    Ok(Client::new_with_client(&args.url, client.build()?))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let c = client(&args)?;
+
+    match args.cmd {
+        Command::Message {
+            text,
+            colour,
+            height,
+            max_height,
+            flash,
+            fit,
+            scroll,
+            ticker,
+            speed,
+            ttl,
+        } => {
+            let text = if let Some(text) = text.filter(|t| t != "-") {
+                text
+            } else {
+                let mut text = String::new();
+                std::io::stdin().read_to_string(&mut text)?;
+                text.trim_end().to_string()
+            };
+
+            c.message(&types::Message {
+                rgb: colour,
+                text: Some(text),
+                spans: None,
+                height,
+                max_height,
+                flash: flash.map(msecs).transpose()?,
+                fit: Some(fit),
+                scroll: (scroll || ticker || speed.is_some()).then(|| {
+                    types::Scroll {
+                        speed,
+                        band: Some(if ticker {
+                            types::Band::Bottom
+                        } else {
+                            types::Band::Full
+                        }),
+                    }
+                }),
+                ttl: ttl.map(secs).transpose()?,
+                until: None,
+            })
+            .await?;
+        }
+        Command::Image { file, clock, align, cover, legibility, ttl } => {
+            let data = std::fs::read(&file)
+                .with_context(|| format!("reading {file:?}"))?;
+
+            c.image(
+                align,
+                clock,
+                Some(cover),
+                legibility,
+                ttl.map(secs).transpose()?,
+                None,
+                data,
+            )
+            .await?;
+        }
+        Command::Font { file, name, ranges } => {
+            let data = std::fs::read(&file)
+                .with_context(|| format!("reading {file:?}"))?;
+            let name = name.unwrap_or_else(|| {
+                file.file_stem()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            });
+
+            c.font(&name, ranges.as_deref(), data).await?;
+        }
+        Command::Power { mode, ttl } => {
+            c.power(&types::Power {
+                mode,
+                ttl: ttl.map(secs).transpose()?,
+                until: None,
+            })
+            .await?;
+        }
+        Command::Clear => {
+            c.clear().await?;
+        }
+        Command::State => {
+            let state = c.state().await?.into_inner();
+
+            println!("{}", serde_json::to_string_pretty(&state)?);
+        }
+        Command::Screenshot { file } => {
+            let mut body = c.screenshot().await?.into_inner().into_inner();
+
+            let mut f = tokio::fs::File::create(&file)
+                .await
+                .with_context(|| format!("creating {file:?}"))?;
+            while let Some(chunk) = body.try_next().await? {
+                f.write_all(&chunk).await?;
+            }
+            f.flush().await?;
+        }
+        Command::Timer { time, label, colour, ttl } => {
+            c.timer(&types::Timer {
+                secs: secs(time)?,
+                label,
+                rgb: colour,
+                ttl: ttl.map(secs).transpose()?,
+                until: None,
+            })
+            .await?;
+        }
+    }
+
+    Ok(())
+}
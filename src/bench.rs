@@ -0,0 +1,116 @@
+/*
+ * Copyright 2024 Oxide Computer Company
+ */
+
+/*
+ * Measure how long it takes to draw a frame much like the clock face, with and
+ * without the glyph cache; e.g., to see what difference a change makes on the
+ * low-power machine that drives the display in the office:
+ *
+ *     $ clock bench
+ */
+
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use image::{Rgb, RgbImage};
+
+use crate::{emit_text, Align, FontStack};
+
+/*
+ * The size of the display in the office:
+ */
+const WIDTH: u32 = 5120;
+const HEIGHT: u32 = 1440;
+
+const FRAMES: i64 = 60;
+
+fn frame(
+    img: &mut RgbImage,
+    fonts: &FontStack,
+    now: DateTime<Utc>,
+    gamma: bool,
+) {
+    img.fill(0);
+
+    let ch = img.height();
+    let ht = ch / 4;
+    let theme = crate::config::Theme::default();
+    let grey = Rgb(theme.date);
+
+    emit_text(
+        &now.format("%d %B %Y").to_string(),
+        Align::Right(img.width() - 1),
+        ch - ht - 10,
+        fonts,
+        ht,
+        grey,
+        img,
+        false,
+        gamma,
+    );
+
+    emit_text(
+        &now.format("%A").to_string(),
+        Align::Left(0),
+        ch - ht - 10,
+        fonts,
+        ht,
+        grey,
+        img,
+        false,
+        gamma,
+    );
+
+    let ht = ch * 10 / 18;
+    emit_text(
+        &now.format("%H:%M:%S").to_string(),
+        Align::Centre(0, img.width()),
+        (ch - ht - (ht / 3)) / 2,
+        fonts,
+        ht,
+        Rgb(theme.time),
+        img,
+        true,
+        gamma,
+    );
+}
+
+/*
+ * Draw a minute's worth of frames, returning the average time per frame.
+ */
+fn run(fonts: &FontStack, cached: bool, gamma: bool) -> Duration {
+    let mut img = RgbImage::new(WIDTH, HEIGHT);
+    let base = Utc.with_ymd_and_hms(2024, 1, 1, 9, 41, 0).unwrap();
+
+    fonts.cache.clear();
+
+    let start = Instant::now();
+    for i in 0..FRAMES {
+        if !cached {
+            fonts.cache.clear();
+        }
+
+        frame(&mut img, fonts, base + chrono::Duration::seconds(i), gamma);
+    }
+    start.elapsed() / FRAMES as u32
+}
+
+pub(crate) fn bench(fonts: &FontStack, gamma: bool) {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+    let uncached = run(fonts, false, gamma);
+
+    let (hits0, misses0) = fonts.cache.stats();
+    let cached = run(fonts, true, gamma);
+    let (hits, misses) = fonts.cache.stats();
+
+    println!("{WIDTH} x {HEIGHT} clock face, {FRAMES} frames:");
+    println!("    without glyph cache: {:8.2} ms per frame", ms(uncached));
+    println!("    with glyph cache:    {:8.2} ms per frame", ms(cached));
+    println!(
+        "    glyph cache: {} hits, {} misses",
+        hits - hits0,
+        misses - misses0
+    );
+}
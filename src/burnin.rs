@@ -0,0 +1,161 @@
+/*
+ * Copyright 2024 Oxide Computer Company
+ */
+
+/*
+ * The display shows much the same thing in the same place all day and all
+ * night, which risks burning the image of the digits into the panel.  To
+ * spread the wear, every frame may be moved by a few pixels: in small steps to
+ * a new position every so often, and in a slow wander around the display.
+ * A full-screen pattern may also be shown for a while at a scheduled time.
+ */
+
+use std::ops::Range;
+
+use chrono::prelude::*;
+use image::{Rgb, RgbImage};
+
+use crate::{config, DateTimeExt};
+
+/*
+ * The colours through which the refresh pattern cycles, one each second:
+ */
+const REFRESH: [Rgb<u8>; 5] = [
+    Rgb([0xff, 0xff, 0xff]),
+    Rgb([0xff, 0x00, 0x00]),
+    Rgb([0x00, 0xff, 0x00]),
+    Rgb([0x00, 0x00, 0xff]),
+    Rgb([0x00, 0x00, 0x00]),
+];
+
+pub(crate) struct BurnIn<'a> {
+    config: &'a config::BurnIn,
+    /*
+     * The offset applied to the last frame we moved:
+     */
+    last: Option<(i32, i32)>,
+}
+
+impl BurnIn<'_> {
+    pub(crate) fn new(config: &config::BurnIn) -> BurnIn {
+        BurnIn { config, last: None }
+    }
+
+    /*
+     * The offset of the display at this time.  Each step moves one pixel
+     * from the last, back and forth along the rows of a square around the
+     * usual position; the wander follows a figure eight.
+     */
+    fn offset(&self, now: DateTime<Utc>) -> (i32, i32) {
+        let (mut dx, mut dy) = (0, 0);
+
+        let s = self.config.shift as i64;
+        if s > 0 {
+            let side = 2 * s + 1;
+            let n = (now.timestamp() / self.config.shift_secs.max(1) as i64)
+                .rem_euclid(side * side);
+            let (row, col) = (n / side, n % side);
+            let col = if row % 2 == 0 { col } else { side - 1 - col };
+            dx += (col - s) as i32;
+            dy += (row - s) as i32;
+        }
+
+        let w = self.config.wander as f64;
+        if w > 0.0 {
+            let period = self.config.wander_mins.max(1) as f64 * 60.0;
+            let t = now.timestamp_millis() as f64 / 1000.0;
+            let phase = (t / period).fract() * std::f64::consts::TAU;
+            dx += (w * phase.sin()).round() as i32;
+            dy += (w * (2.0 * phase).sin() / 2.0).round() as i32;
+        }
+
+        (dx, dy)
+    }
+
+    /*
+     * Move the frame to where it should be at this time.  Returns the rows of
+     * the display which may have changed, given the rows of the frame which
+     * the caller says have changed: if the offset has changed since the last
+     * frame, that will be all of them.
+     */
+    pub(crate) fn apply(
+        &mut self,
+        img: &mut RgbImage,
+        now: DateTime<Utc>,
+        damage: Option<Range<u32>>,
+    ) -> Option<Range<u32>> {
+        if self.refreshing(now) {
+            /*
+             * The refresh pattern covers the whole display, so leave it where
+             * it is.
+             */
+            self.last = None;
+            return None;
+        }
+
+        let (dx, dy) = self.offset(now);
+        let moved = self.last.replace((dx, dy)) != Some((dx, dy));
+
+        translate(img, dx, dy);
+
+        damage.filter(|_| !moved).map(|rows| {
+            let h = img.height();
+            rows.start.saturating_add_signed(dy).min(h)
+                ..rows.end.saturating_add_signed(dy).min(h)
+        })
+    }
+
+    /*
+     * Whether the refresh pattern should be on the display at this time:
+     */
+    pub(crate) fn refreshing(&self, now: DateTime<Utc>) -> bool {
+        self.config
+            .refresh
+            .as_ref()
+            .is_some_and(|r| r.active(now.home().time()))
+    }
+
+    pub(crate) fn refresh_pattern(
+        &self,
+        img: &mut RgbImage,
+        now: DateTime<Utc>,
+    ) {
+        let c =
+            REFRESH[now.timestamp().rem_euclid(REFRESH.len() as i64) as usize];
+        for p in img.pixels_mut() {
+            *p = c;
+        }
+    }
+}
+
+/*
+ * Move the contents of the image right by "dx" pixels and down by "dy" pixels,
+ * filling the space left behind with black.
+ */
+fn translate(img: &mut RgbImage, dx: i32, dy: i32) {
+    let stride = img.width() as usize * 3;
+    let raw: &mut [u8] = img;
+
+    let d = (dy.unsigned_abs() as usize * stride).min(raw.len());
+    if dy > 0 {
+        raw.copy_within(..raw.len() - d, d);
+        raw[..d].fill(0);
+    } else if dy < 0 {
+        raw.copy_within(d.., 0);
+        let end = raw.len() - d;
+        raw[end..].fill(0);
+    }
+
+    let d = (dx.unsigned_abs() as usize * 3).min(stride);
+    if d > 0 {
+        for row in raw.chunks_exact_mut(stride) {
+            if dx > 0 {
+                row.copy_within(..stride - d, d);
+                row[..d].fill(0);
+            } else {
+                row.copy_within(d.., 0);
+                row[stride - d..].fill(0);
+            }
+        }
+    }
+}
@@ -0,0 +1,312 @@
+/*
+ * Copyright 2024 Oxide Computer Company
+ */
+
+/*
+ * Colour fonts, such as those for emoji, draw some or all of their glyphs in
+ * their own colours rather than in the colour of the text.  There are two
+ * kinds that we understand:
+ *
+ *  - bitmap fonts ("CBDT" or "sbix" tables), in which each glyph is a PNG image
+ *    at one or more fixed sizes, which we scale to the size of the text;
+ *
+ *  - layered fonts ("COLR" and "CPAL" tables, version 0), in which each glyph
+ *    is a stack of ordinary outline glyphs, each drawn in a colour from a
+ *    palette.
+ *
+ * rusttype draws only outlines, so we find and draw colour glyphs here.
+ */
+
+use std::collections::HashMap;
+
+use image::{imageops::FilterType, Rgba, RgbaImage};
+use owned_ttf_parser::AsFaceRef;
+use rusttype::{point, Font, GlyphId, Scale};
+
+fn be_u16(data: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn be_u32(data: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(off..off + 4)?.try_into().ok()?))
+}
+
+/*
+ * Find a table in the first font in the file, returning its contents.
+ */
+fn table<'a>(data: &'a [u8], tag: &[u8; 4]) -> Option<&'a [u8]> {
+    let font =
+        if data.starts_with(b"ttcf") { be_u32(data, 12)? as usize } else { 0 };
+
+    let count = be_u16(data, font + 4)? as usize;
+    (0..count).find_map(|i| {
+        let rec = font + 12 + i * 16;
+        if data.get(rec..rec + 4)? != tag {
+            return None;
+        }
+        let off = be_u32(data, rec + 8)? as usize;
+        let len = be_u32(data, rec + 12)? as usize;
+        data.get(off..off.checked_add(len)?)
+    })
+}
+
+/*
+ * The colours of the first palette in the "CPAL" table.
+ */
+fn palette(cpal: &[u8]) -> Option<Vec<Rgba<u8>>> {
+    let entries = be_u16(cpal, 2)? as usize;
+    let records = be_u32(cpal, 8)? as usize;
+    let first = be_u16(cpal, 12)? as usize;
+
+    (0..entries)
+        .map(|i| {
+            /*
+             * Each colour is stored as blue, green, red, alpha:
+             */
+            let off = records + (first + i) * 4;
+            let c = cpal.get(off..off + 4)?;
+            Some(Rgba([c[2], c[1], c[0], c[3]]))
+        })
+        .collect()
+}
+
+/*
+ * Each colour glyph in the "COLR" table, with the glyphs which make up its
+ * layers from bottom to top, and the colour of each layer.  Layers in the
+ * special palette entry 0xFFFF take the colour of the text, which we do not
+ * know until the glyph is drawn, so have no colour here.  Version 1 tables
+ * begin with the same records as version 0; we ignore the gradients and
+ * transforms that follow them.
+ */
+fn layers(
+    colr: &[u8],
+    palette: &[Rgba<u8>],
+) -> Option<HashMap<u16, Vec<(u16, Option<Rgba<u8>>)>>> {
+    let bases = be_u16(colr, 2)? as usize;
+    let base_off = be_u32(colr, 4)? as usize;
+    let layer_off = be_u32(colr, 8)? as usize;
+
+    (0..bases)
+        .map(|i| {
+            let rec = base_off + i * 6;
+            let gid = be_u16(colr, rec)?;
+            let first = be_u16(colr, rec + 2)? as usize;
+            let count = be_u16(colr, rec + 4)? as usize;
+
+            let layers = (first..first + count)
+                .map(|l| {
+                    let rec = layer_off + l * 4;
+                    let lgid = be_u16(colr, rec)?;
+                    let idx = be_u16(colr, rec + 2)?;
+                    Some((lgid, palette.get(idx as usize).copied()))
+                })
+                .collect::<Option<Vec<_>>>()?;
+
+            Some((gid, layers))
+        })
+        .collect()
+}
+
+pub(crate) struct ColourFont {
+    /*
+     * Whether the font has bitmap glyphs, which we decode from the font file
+     * held by rusttype as they are needed:
+     */
+    bitmaps: bool,
+    layers: HashMap<u16, Vec<(u16, Option<Rgba<u8>>)>>,
+}
+
+/*
+ * A colour glyph, and the offset of its top left corner from the origin of the
+ * glyph on the baseline.  Where a glyph is drawn partly in the colour of the
+ * text, "text" has the proportion of each pixel (from 0 to 255) which should
+ * be that colour rather than the colour in the image.
+ */
+pub(crate) struct ColourGlyph {
+    pub x: i32,
+    pub y: i32,
+    pub img: RgbaImage,
+    pub text: Option<Vec<u8>>,
+}
+
+impl ColourFont {
+    /*
+     * Look for colour glyphs in a font, returning None if it has none.
+     */
+    pub(crate) fn parse(data: &[u8]) -> Option<ColourFont> {
+        let bitmaps =
+            table(data, b"CBDT").is_some() || table(data, b"sbix").is_some();
+
+        let layers = table(data, b"COLR")
+            .zip(table(data, b"CPAL").and_then(palette))
+            .and_then(|(colr, palette)| layers(colr, &palette))
+            .unwrap_or_default();
+
+        if !bitmaps && layers.is_empty() {
+            return None;
+        }
+
+        Some(ColourFont { bitmaps, layers })
+    }
+
+    /*
+     * Draw a glyph in colour, at the same scale as rusttype would draw it in
+     * outline.  Returns None if the font has no colour version of the glyph,
+     * in which case the outline should be used.
+     */
+    pub(crate) fn glyph(
+        &self,
+        font: &Font,
+        c: char,
+        scale: f32,
+    ) -> Option<ColourGlyph> {
+        let gid = font.glyph(c).id();
+        if gid == GlyphId(0) {
+            return None;
+        }
+
+        if let Some(layers) = self.layers.get(&gid.0) {
+            return self.layered(font, layers, scale);
+        }
+
+        self.bitmap(font, gid, scale)
+    }
+
+    fn bitmap(
+        &self,
+        font: &Font,
+        gid: GlyphId,
+        scale: f32,
+    ) -> Option<ColourGlyph> {
+        if !self.bitmaps {
+            return None;
+        }
+        let face: &ttf_parser::Face = match font {
+            Font::Ref(face) => face.as_ref(),
+            Font::Owned(face) => face.as_face_ref(),
+        };
+
+        /*
+         * A uniform rusttype scale is the height in pixels from the lowest
+         * descender to the highest ascender, rather than the size of the em
+         * square by which bitmaps are chosen:
+         */
+        let vm = font.v_metrics_unscaled();
+        let ppem =
+            scale * font.units_per_em() as f32 / (vm.ascent - vm.descent);
+        if !(ppem.is_finite() && ppem > 0.0) {
+            return None;
+        }
+
+        let rg = face.glyph_raster_image(
+            ttf_parser::GlyphId(gid.0),
+            ppem.ceil().clamp(1.0, u16::MAX as f32) as u16,
+        )?;
+        if rg.format != ttf_parser::RasterImageFormat::PNG
+            || rg.pixels_per_em == 0
+        {
+            return None;
+        }
+
+        let img = image::load_from_memory(rg.data).ok()?.to_rgba8();
+
+        /*
+         * Bitmaps come in only a few sizes, so scale the nearest one to the
+         * size of the text.  The offset of the bitmap is to its bottom left
+         * corner, with y increasing upwards.  A glyph is not much bigger than
+         * the em square, so a font that claims otherwise (e.g., with a bitmap
+         * for one pixel per em) must not make us scale its bitmap to an
+         * enormous size.
+         */
+        let f = ppem / rg.pixels_per_em as f32;
+        let limit = (2.0 * scale).ceil().max(1.0);
+        let width = (rg.width as f32 * f).round().clamp(1.0, limit) as u32;
+        let height = (rg.height as f32 * f).round().clamp(1.0, limit) as u32;
+        let img = if (width, height) == img.dimensions() {
+            img
+        } else {
+            image::imageops::resize(&img, width, height, FilterType::Triangle)
+        };
+
+        Some(ColourGlyph {
+            x: (rg.x as f32 * f).round() as i32,
+            y: -((rg.y as f32 * f).round() as i32) - height as i32,
+            img,
+            text: None,
+        })
+    }
+
+    fn layered(
+        &self,
+        font: &Font,
+        layers: &[(u16, Option<Rgba<u8>>)],
+        scale: f32,
+    ) -> Option<ColourGlyph> {
+        let glyphs = layers
+            .iter()
+            .filter_map(|&(gid, rgba)| {
+                let g = font
+                    .glyph(GlyphId(gid))
+                    .scaled(Scale::uniform(scale))
+                    .positioned(point(0.0, 0.0));
+                let bb = g.pixel_bounding_box()?;
+                Some((g, bb, rgba))
+            })
+            .collect::<Vec<_>>();
+
+        let x0 = glyphs.iter().map(|(_, bb, _)| bb.min.x).min()?;
+        let y0 = glyphs.iter().map(|(_, bb, _)| bb.min.y).min()?;
+        let x1 = glyphs.iter().map(|(_, bb, _)| bb.max.x).max()?;
+        let y1 = glyphs.iter().map(|(_, bb, _)| bb.max.y).max()?;
+        let width = (x1 - x0) as u32;
+        let height = (y1 - y0) as u32;
+
+        /*
+         * Paint each layer over those beneath it.  For each pixel, keep the
+         * red, green, and blue of the palette colours and the amount of text
+         * colour painted so far (all premultiplied by their coverage), and
+         * the total coverage:
+         */
+        let mut acc = vec![[0f32; 5]; (width * height) as usize];
+        for (g, bb, rgba) in glyphs {
+            g.draw(|x, y, v| {
+                let x = x + (bb.min.x - x0) as u32;
+                let y = y + (bb.min.y - y0) as u32;
+                let p = &mut acc[(y * width + x) as usize];
+                let a = v.clamp(0.0, 1.0)
+                    * rgba.map_or(1.0, |rgba| rgba[3] as f32 / 255.0);
+
+                for q in p.iter_mut() {
+                    *q *= 1.0 - a;
+                }
+                match rgba {
+                    Some(rgba) => {
+                        for i in 0..3 {
+                            p[i] += rgba[i] as f32 * a;
+                        }
+                    }
+                    None => p[3] += a,
+                }
+                p[4] += a;
+            });
+        }
+
+        let mut img = RgbaImage::new(width, height);
+        let mut text = Vec::with_capacity(acc.len());
+        for (px, [r, g, b, t, a]) in img.pixels_mut().zip(acc) {
+            let own = a - t;
+            let c = |v: f32| {
+                if own > 0.0 {
+                    (v / own).round().clamp(0.0, 255.0) as u8
+                } else {
+                    0
+                }
+            };
+            *px = Rgba([c(r), c(g), c(b), (a * 255.0).round() as u8]);
+            text.push(if a > 0.0 { (t / a * 255.0).round() as u8 } else { 0 });
+        }
+        let text = text.iter().any(|&t| t > 0).then_some(text);
+
+        Some(ColourGlyph { x: x0, y: y0, img, text })
+    }
+}
@@ -0,0 +1,475 @@
+/*
+ * Copyright 2024 Oxide Computer Company
+ */
+
+use std::{
+    collections::HashMap,
+    iter::once,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Deserializer};
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub http: Http,
+    #[serde(default)]
+    pub auth: Auth,
+    pub tls: Option<Tls>,
+    pub control: Option<Control>,
+    #[serde(default)]
+    pub watchdog: Watchdog,
+    #[serde(default)]
+    pub display: Display,
+    #[serde(default)]
+    pub fonts: Vec<Font>,
+    #[serde(default)]
+    pub themes: HashMap<String, Theme>,
+    #[serde(default)]
+    pub burn_in: BurnIn,
+    pub power: Option<Power>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config {path:?}"))?;
+        let config: Config = toml::from_str(&s)
+            .with_context(|| format!("parsing config {path:?}"))?;
+
+        let d = &config.display;
+        for name in once(&d.theme).chain(d.night.as_ref().map(|n| &n.theme)) {
+            config.theme(name).with_context(|| format!("config {path:?}"))?;
+        }
+
+        /*
+         * A transition between the digits of the clock must be over before
+         * the next second begins:
+         */
+        if d.transition.digits && d.transition.ms >= 1000 {
+            bail!(
+                "config {path:?}: a transition used for the digits of the \
+                clock must take less than 1000 ms"
+            );
+        }
+
+        Ok(config)
+    }
+
+    /*
+     * Look up a theme by name, among those in the configuration and then
+     * those built into the clock.
+     */
+    pub fn theme(&self, name: &str) -> Result<Theme> {
+        if let Some(t) = self.themes.get(name) {
+            return Ok(t.clone());
+        }
+
+        match name {
+            "day" => Ok(Theme::default()),
+            "night" => Ok(Theme { brightness: 0.25, ..Default::default() }),
+            _ => Err(anyhow!("unknown theme {name:?}")),
+        }
+    }
+
+    /*
+     * The name of the theme to use at this local time of day:
+     */
+    pub fn theme_name_at(&self, t: NaiveTime) -> &str {
+        match &self.display.night {
+            Some(n) if n.contains(t) => &n.theme,
+            _ => &self.display.theme,
+        }
+    }
+
+    pub fn theme_at(&self, t: NaiveTime) -> Theme {
+        /*
+         * The names of the themes in use were checked when the configuration
+         * was loaded:
+         */
+        self.theme(self.theme_name_at(t)).unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Http {
+    /*
+     * The HTTP server may be disabled entirely, e.g., if the display is only
+     * to be controlled through the local control socket.
+     */
+    pub enabled: bool,
+    /*
+     * The address and port on which to listen; e.g., "[::]:8888" to accept
+     * both IPv4 and IPv6 connections.
+     */
+    pub listen: SocketAddr,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            enabled: true,
+            listen: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 8888),
+        }
+    }
+}
+
+/*
+ * A Unix domain socket through which local programs may control the display
+ * without going through the HTTP server.  Access is governed by the
+ * permissions on the socket, rather than by tokens.
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Control {
+    pub socket: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Watchdog {
+    /*
+     * How long may pass without a frame being painted before the clock is
+     * considered unhealthy.  This also limits the flash period of messages to
+     * less than half this time.
+     */
+    pub stale_secs: u64,
+    /*
+     * Whether to restart the render loop if it fails.  If the render loop
+     * stops painting frames altogether, the process will exit so that the
+     * service manager can restart it.
+     */
+    pub restart: bool,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog { stale_secs: 10, restart: false }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Display {
+    /*
+     * How many frames to draw each second while something on the display is
+     * moving, such as a scrolling message.  Otherwise, the display is only
+     * redrawn once a second.
+     */
+    pub frame_rate: u32,
+    /*
+     * Whether to blend text onto the display with gamma correction.  This
+     * makes the edges of text look better over images and other coloured
+     * backgrounds, at the cost of some CPU time.
+     */
+    pub gamma_correct: bool,
+    /*
+     * The name of the theme in which to draw the clock, except at night:
+     */
+    pub theme: String,
+    pub night: Option<Night>,
+    pub transition: Transition,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display {
+            frame_rate: 30,
+            gamma_correct: false,
+            theme: "day".to_string(),
+            night: None,
+            transition: Default::default(),
+        }
+    }
+}
+
+/*
+ * How to move from one thing on the display to the next; e.g., from the clock
+ * to a message.  By default, there is no transition.
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Transition {
+    pub kind: TransitionKind,
+    /*
+     * How long the transition takes, in milliseconds:
+     */
+    pub ms: u32,
+    /*
+     * Whether to use the transition each second, as the digits of the clock
+     * (or a countdown) change, too:
+     */
+    pub digits: bool,
+}
+
+impl Default for Transition {
+    fn default() -> Self {
+        Transition { kind: TransitionKind::Cut, ms: 400, digits: false }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionKind {
+    Cut,
+    Crossfade,
+    Slide,
+    Dissolve,
+}
+
+/*
+ * The hours, in the local time of the machine, during which to use a different
+ * theme; e.g., a dim one, so that the display is not so bright in an empty
+ * office.  The night may run past midnight.
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Night {
+    #[serde(default = "Night::default_theme")]
+    pub theme: String,
+    #[serde(deserialize_with = "time_of_day")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "time_of_day")]
+    pub end: NaiveTime,
+}
+
+impl Night {
+    fn default_theme() -> String {
+        "night".to_string()
+    }
+
+    pub fn contains(&self, t: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+}
+
+/*
+ * Measures to avoid burning the image of the clock into the display.  By
+ * default, the display is not moved and there is no refresh pattern.
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct BurnIn {
+    /*
+     * Move the whole display by up to this many pixels in each direction,
+     * one pixel at a time, every "shift_secs" seconds:
+     */
+    pub shift: u32,
+    pub shift_secs: u64,
+    /*
+     * Also wander slowly up to this many pixels either side of the usual
+     * position, taking "wander_mins" minutes to complete each circuit:
+     */
+    pub wander: u32,
+    pub wander_mins: u64,
+    pub refresh: Option<Refresh>,
+}
+
+impl Default for BurnIn {
+    fn default() -> Self {
+        BurnIn {
+            shift: 0,
+            shift_secs: 60,
+            wander: 0,
+            wander_mins: 60,
+            refresh: None,
+        }
+    }
+}
+
+/*
+ * Fill the display with a cycle of solid colours for "secs" seconds, starting
+ * at this local time of day each day:
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Refresh {
+    #[serde(deserialize_with = "time_of_day")]
+    pub at: NaiveTime,
+    #[serde(default = "Refresh::default_secs")]
+    pub secs: u64,
+}
+
+impl Refresh {
+    fn default_secs() -> u64 {
+        60
+    }
+
+    pub fn active(&self, t: NaiveTime) -> bool {
+        (t - self.at).num_seconds().rem_euclid(24 * 60 * 60) < self.secs as i64
+    }
+}
+
+/*
+ * When the display should be lit, in the local time of the machine; at other
+ * times, it is blanked.  If "on" is later than "off", the display is lit
+ * overnight, from "on" on each of the listed days until "off" the next day.
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Power {
+    #[serde(default = "Power::every_day")]
+    pub days: Vec<Weekday>,
+    #[serde(deserialize_with = "time_of_day")]
+    pub on: NaiveTime,
+    #[serde(deserialize_with = "time_of_day")]
+    pub off: NaiveTime,
+    /*
+     * Whether to light the display anyway while a message, image, or timer
+     * has been posted:
+     */
+    #[serde(default)]
+    pub wake_on_message: bool,
+}
+
+impl Power {
+    fn every_day() -> Vec<Weekday> {
+        use Weekday::*;
+
+        vec![Mon, Tue, Wed, Thu, Fri, Sat, Sun]
+    }
+
+    pub fn lit(&self, t: NaiveDateTime) -> bool {
+        let day = |d: NaiveDate| self.days.contains(&d.weekday());
+        let time = t.time();
+
+        if self.on <= self.off {
+            day(t.date()) && self.on <= time && time < self.off
+        } else {
+            (day(t.date()) && time >= self.on)
+                || (t.date().pred_opt().is_some_and(day) && time < self.off)
+        }
+    }
+}
+
+/*
+ * Times of day are written as hours and minutes; e.g., "19:30".
+ */
+fn time_of_day<'de, D>(d: D) -> std::result::Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    NaiveTime::parse_from_str(&s, "%H:%M").map_err(serde::de::Error::custom)
+}
+
+/*
+ * The colours in which to draw the clock, and the brightness of the whole
+ * display, which also applies to any message or image.  The default is the
+ * usual Oxide green time, with the date in grey.
+ */
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct Theme {
+    pub time: [u8; 3],
+    pub date: [u8; 3],
+    /*
+     * The line between clocks, if there is more than one:
+     */
+    pub separator: [u8; 3],
+    /*
+     * Between 0 and 1:
+     */
+    pub brightness: f32,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            time: [0x48, 0xd5, 0x97],
+            date: [0x7d, 0x83, 0x85],
+            separator: [0xc8, 0xc8, 0xc8],
+            brightness: 1.0,
+        }
+    }
+}
+
+/*
+ * A font to use in addition to those built into the clock.  The font is
+ * preferred for characters in "ranges", each a hexadecimal code point or an
+ * inclusive range of code points (e.g., "U+1F600", "0590-05FF"), and is
+ * otherwise used for any character that only it can draw.
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Font {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub ranges: Vec<String>,
+}
+
+/*
+ * If no tokens are configured, the API is open to anybody who can reach it.
+ * Once at least one token is configured, every request that changes what is
+ * on the display must present a token with the appropriate permission.
+ */
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Auth {
+    #[serde(default)]
+    pub tokens: Vec<Token>,
+    /*
+     * Whether requests that only read state (e.g., "GET /state") also require
+     * a token with the "read" permission:
+     */
+    #[serde(default)]
+    pub protect_reads: bool,
+}
+
+/*
+ * If present, the HTTP server will only accept HTTPS connections, using this
+ * certificate chain and private key (both in PEM format).
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Token {
+    /*
+     * A name for the holder of the token, recorded as the author of anything
+     * they post:
+     */
+    pub name: String,
+    pub secret: String,
+    /*
+     * If not specified, the token may be used for anything.
+     */
+    #[serde(default = "Permission::all")]
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Read,
+    Message,
+    Image,
+    Timer,
+    Clear,
+    Font,
+    Power,
+}
+
+impl Permission {
+    fn all() -> Vec<Permission> {
+        use Permission::*;
+
+        vec![Read, Message, Image, Timer, Clear, Font, Power]
+    }
+}
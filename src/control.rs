@@ -0,0 +1,227 @@
+/*
+ * Copyright 2024 Oxide Computer Company
+ */
+
+/*
+ * A control channel on a Unix domain socket, through which programs running on
+ * the clock itself can perform the same operations as the HTTP API.  Each
+ * request is a single line of JSON, with an "op" property naming the
+ * operation; e.g.,
+ *
+ *     {"op": "message", "text": "Hello!", "rgb": [255, 0, 0], "height": 300}
+ *     {"op": "image", "path": "/tmp/picture.png", "ttl": 60}
+ *     {"op": "image", "path": "/tmp/picture.png", "clock": "right"}
+ *     {"op": "timer", "secs": 300, "label": "Tea"}
+ *     {"op": "font", "path": "/tmp/hebrew.ttf", "name": "hebrew",
+ *         "ranges": "0590-05FF"}
+ *     {"op": "power", "mode": "off", "ttl": 3600}
+ *     {"op": "clear"}
+ *     {"op": "state"}
+ *     {"op": "screenshot", "path": "/tmp/clock.png"}
+ *
+ * Each request receives a single line of JSON in response, containing either
+ * "ok": true or an "error" message.
+ *
+ * A screenshot is written by the clock, rather than by the program on the
+ * other end of the socket, so it will not replace a file that already exists.
+ */
+
+use std::{path::Path, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use slog::{error, info, o, warn, Logger};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{http, App};
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    Message(http::Message),
+    Image {
+        path: PathBuf,
+        #[serde(flatten)]
+        options: http::ImageOptions,
+    },
+    Timer(http::Timer),
+    Font {
+        path: PathBuf,
+        #[serde(flatten)]
+        options: http::FontOptions,
+    },
+    Power(http::Power),
+    Clear,
+    State,
+    Screenshot {
+        path: PathBuf,
+    },
+}
+
+#[derive(Serialize, Default)]
+struct Reply {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<http::State>,
+}
+
+pub(crate) fn listen(path: &Path) -> Result<UnixListener> {
+    /*
+     * Remove any socket left behind by a previous instance:
+     */
+    match std::fs::remove_file(path) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => {
+            return Err(e).with_context(|| format!("removing {path:?}"));
+        }
+    }
+
+    UnixListener::bind(path).with_context(|| format!("binding {path:?}"))
+}
+
+pub(crate) async fn server(app: Arc<App>, listener: UnixListener) {
+    loop {
+        let sock = match listener.accept().await {
+            Ok((sock, _)) => sock,
+            Err(e) => {
+                /*
+                 * Failures here (e.g., running out of file descriptors) are
+                 * generally transient, so pause briefly and keep accepting
+                 * rather than abandoning the socket altogether.
+                 */
+                warn!(app.log, "control socket accept error: {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        /*
+         * Record the user on the other end of the socket as the author of
+         * anything they post:
+         */
+        let by = match sock.peer_cred() {
+            Ok(cred) => format!("uid {} (control socket)", cred.uid()),
+            Err(_) => "control socket".to_string(),
+        };
+        let log = app.log.new(o!("control" => by.clone()));
+
+        let app = app.clone();
+        tokio::task::spawn(async move {
+            if let Err(e) = connection(&app, &log, sock, by).await {
+                error!(log, "control connection error: {e:?}");
+            }
+        });
+    }
+}
+
+async fn connection(
+    app: &App,
+    log: &Logger,
+    sock: UnixStream,
+    by: String,
+) -> Result<()> {
+    let (r, mut w) = sock.into_split();
+    let mut lines = BufReader::new(r).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let reply = match serde_json::from_str::<Request>(&line) {
+            Ok(req) => match perform(app, log, req, by.clone()).await {
+                Ok(state) => Reply { ok: true, state, ..Default::default() },
+                Err(e) => Reply { error: Some(e), ..Default::default() },
+            },
+            Err(e) => Reply {
+                error: Some(format!("invalid request: {e}")),
+                ..Default::default()
+            },
+        };
+
+        let mut out = serde_json::to_vec(&reply)?;
+        out.push(b'\n');
+        w.write_all(&out).await?;
+    }
+
+    Ok(())
+}
+
+async fn perform(
+    app: &App,
+    log: &Logger,
+    req: Request,
+    by: String,
+) -> std::result::Result<Option<http::State>, String> {
+    match req {
+        Request::Message(m) => {
+            info!(log, "message");
+            let msg = m
+                .into_message(app, by)
+                .await
+                .map_err(|e| e.external_message)?;
+            http::post_message(app, msg);
+        }
+        Request::Image { path, options } => {
+            info!(log, "image from {path:?}");
+            let posted = options
+                .lifetime()
+                .posted(by)
+                .map_err(|e| e.external_message)?;
+            let data = tokio::fs::read(&path)
+                .await
+                .map_err(|e| format!("reading {path:?}: {e}"))?;
+            http::post_image(app, log, &data, &options, posted)
+                .await
+                .map_err(|e| e.external_message)?;
+        }
+        Request::Timer(t) => {
+            info!(log, "timer");
+            let timer = t.into_timer(by).map_err(|e| e.external_message)?;
+            http::post_timer(app, timer);
+        }
+        Request::Font { path, options } => {
+            info!(log, "font from {path:?}");
+            let data = tokio::fs::read(&path)
+                .await
+                .map_err(|e| format!("reading {path:?}: {e}"))?;
+            http::post_font(app, log, data, &options)
+                .map_err(|e| e.external_message)?;
+        }
+        Request::Power(p) => {
+            info!(log, "power");
+            let power = p.into_power(by).map_err(|e| e.external_message)?;
+            http::post_power(app, power);
+        }
+        Request::Clear => {
+            info!(log, "clear");
+            http::clear_all(app);
+        }
+        Request::State => {
+            return Ok(Some(http::current_state(app)));
+        }
+        Request::Screenshot { path } => {
+            info!(log, "screenshot to {path:?}");
+            let png =
+                http::screenshot_png(app).map_err(|e| format!("png: {e}"))?;
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await
+                .map_err(|e| format!("creating {path:?}: {e}"))?;
+            f.write_all(&png)
+                .await
+                .map_err(|e| format!("writing {path:?}: {e}"))?;
+        }
+    }
+
+    Ok(None)
+}
@@ -2,7 +2,7 @@
  * Copyright 2024 Oxide Computer Company
  */
 
-use std::{ffi::CStr, mem::size_of, time::Instant};
+use std::{ffi::CStr, mem::size_of, ops::Range, time::Instant};
 
 use anyhow::{bail, Result};
 use image::RgbImage;
@@ -118,7 +118,38 @@ impl Framebuffer {
         })
     }
 
-    pub fn apply(&mut self, img: &RgbImage) {
+    /*
+     * Draw the image on the display, returning the number of stripes that
+     * needed to be written.
+     */
+    pub fn apply(&mut self, img: &RgbImage) -> Result<usize> {
+        self.update(img, 0..self.height(), true)
+    }
+
+    /*
+     * Update the display from only the specified rows of the image, for use
+     * when the caller knows that nothing outside those rows has changed since
+     * the last frame; e.g., when a message is scrolling across the display
+     * many times a second.  Examining every pixel of an enormous display for
+     * every frame would be prohibitively expensive.
+     *
+     * The dirty stripes are drawn in order, rather than shuffled, as the
+     * dissolve effect makes moving text appear to shimmer.
+     */
+    pub fn apply_rows(
+        &mut self,
+        img: &RgbImage,
+        rows: Range<usize>,
+    ) -> Result<usize> {
+        self.update(img, rows, false)
+    }
+
+    fn update(
+        &mut self,
+        img: &RgbImage,
+        rows: Range<usize>,
+        shuffle: bool,
+    ) -> Result<usize> {
         let this_draw = Instant::now();
         if this_draw.saturating_duration_since(self.last_clear).as_secs() > 15 {
             /*
@@ -137,11 +168,19 @@ impl Framebuffer {
          * framebuffer into stripes, so that we can draw only the portions of
          * the display that are dirty.
          */
-        const CHUNKS: usize = 256;
+        const CHUNKS: usize = crate::STRIPES;
         let mut buckets = [false; CHUNKS];
         let chsz = (self.width * self.height) as usize / CHUNKS;
 
-        for (idx, px) in img.as_raw().chunks(3).enumerate() {
+        let width = self.width() as usize;
+        let first = rows.start.min(self.height()) * width;
+        let last = rows.end.min(self.height()) * width;
+
+        for (idx, px) in img.as_raw()[first * 3..last * 3]
+            .chunks(3)
+            .enumerate()
+            .map(|(i, px)| (first + i, px))
+        {
             if self.shadow[idx * 4 + 2] != px[0] {
                 self.shadow[idx * 4 + 2] = px[0];
                 buckets[idx / chsz] = true;
@@ -178,21 +217,40 @@ impl Framebuffer {
          *
          * Use the Fisher-Yates shuffle to randomise the draw order:
          */
-        for i in 1..indexes.len() {
-            let j = unsafe { arc4random_uniform((i + 1) as u32) } as usize;
-            indexes.swap(i, j);
+        if shuffle {
+            for i in 1..indexes.len() {
+                let j = unsafe { arc4random_uniform((i + 1) as u32) } as usize;
+                indexes.swap(i, j);
+            }
         }
 
+        let ndirty = indexes.len();
         let buf = self.shadow.as_ptr() as *const c_void;
         for idx in indexes {
             let chsz = ((self.width * self.height) as usize / CHUNKS) * 4;
             let offs = (self.baseaddr as i64) + (idx as i64) * (chsz as i64);
             let buf = (buf as usize) + (idx as usize) * (chsz as usize);
 
-            unsafe { libc::pwrite(self.fd, buf as *const c_void, chsz, offs) };
+            let r = unsafe {
+                libc::pwrite(self.fd, buf as *const c_void, chsz, offs)
+            };
+            if r < 0 {
+                let e = std::io::Error::last_os_error();
+
+                /*
+                 * The shadow copy no longer reflects what is on the display, so
+                 * make sure we redraw everything next time.
+                 */
+                self.clear = true;
+                bail!("framebuffer write: {e}");
+            } else if r as usize != chsz {
+                self.clear = true;
+                bail!("framebuffer write: short write ({r} of {chsz})");
+            }
         }
 
         self.clear = false;
+        Ok(ndirty)
     }
 
     pub fn height(&self) -> usize {
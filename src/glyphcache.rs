@@ -0,0 +1,238 @@
+/*
+ * Copyright 2024 Oxide Computer Company
+ */
+
+/*
+ * Rasterising glyphs is by far the most expensive part of drawing text, and
+ * the clock draws the same few glyphs at the same few sizes over and over
+ * again.  Keep the coverage bitmap of each glyph we rasterise, so that drawing
+ * it again is just a matter of blending that bitmap onto the image.
+ */
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use image::Rgb;
+use rusttype::{point, Font, Scale};
+
+use crate::{colour::ColourFont, utils::MutexExt};
+
+/*
+ * Once the coverage bitmaps in the cache take up this many bytes, start again
+ * with an empty cache rather than grow without bound as messages of all sizes
+ * come and go.  A single glyph the height of the display takes over a
+ * megabyte, so this must be a limit on size rather than on the number of
+ * glyphs.
+ */
+const MAX_BYTES: usize = 32 * 1024 * 1024;
+
+/*
+ * The number of scales for which to remember the width of numbers:
+ */
+const MAX_WIDTHS: usize = 1024;
+
+/*
+ * Glyphs are identified by the index of their font in the font stack, the
+ * character, and the bits of the floating point scale:
+ */
+type Key = (usize, char, u32);
+
+pub(crate) struct Coverage {
+    /*
+     * The offset of the top left of the bitmap from the origin of the glyph,
+     * which is on the baseline:
+     */
+    pub x: i32,
+    pub y: i32,
+    width: u32,
+    alpha: Vec<u8>,
+    /*
+     * For glyphs from colour fonts, the colour of each pixel, and how much of
+     * the colour of the text (from 0 to 255) to mix into it; other glyphs are
+     * drawn entirely in the colour of the text:
+     */
+    colour: Option<Vec<(Rgb<u8>, u8)>>,
+}
+
+impl Coverage {
+    fn rasterise(
+        font: &Font,
+        colour: Option<&ColourFont>,
+        c: char,
+        scale: f32,
+    ) -> Coverage {
+        if let Some(cg) = colour.and_then(|cf| cf.glyph(font, c, scale)) {
+            let (alpha, colour) = cg
+                .img
+                .pixels()
+                .enumerate()
+                .map(|(i, p)| {
+                    let text = cg.text.as_ref().map_or(0, |t| t[i]);
+                    (p[3], (Rgb([p[0], p[1], p[2]]), text))
+                })
+                .unzip();
+
+            return Coverage {
+                x: cg.x,
+                y: cg.y,
+                width: cg.img.width(),
+                alpha,
+                colour: Some(colour),
+            };
+        }
+
+        let g = font
+            .glyph(c)
+            .scaled(Scale::uniform(scale))
+            .positioned(point(0.0, 0.0));
+
+        let Some(bb) = g.pixel_bounding_box() else {
+            /*
+             * Some glyphs, like spaces, have nothing to draw.
+             */
+            return Coverage {
+                x: 0,
+                y: 0,
+                width: 0,
+                alpha: Vec::new(),
+                colour: None,
+            };
+        };
+
+        let width = bb.width() as u32;
+        let mut alpha = vec![0u8; (width * bb.height() as u32) as usize];
+        g.draw(|x, y, v| {
+            alpha[(y * width + x) as usize] =
+                (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+        });
+
+        Coverage { x: bb.min.x, y: bb.min.y, width, alpha, colour: None }
+    }
+
+    /*
+     * Each pixel with any coverage at all, relative to the top left of the
+     * bitmap, along with that coverage between 0 and 1.  If the glyph has its
+     * own colours, the colour of the pixel is included, with the proportion
+     * (between 0 and 1) of the colour of the text to mix into it:
+     */
+    pub fn pixels(
+        &self,
+    ) -> impl Iterator<Item = (u32, u32, f32, Option<(Rgb<u8>, f32)>)> + '_
+    {
+        self.alpha.iter().enumerate().filter(|(_, &a)| a > 0).map(|(i, &a)| {
+            let colour =
+                self.colour.as_ref().map(|c| (c[i].0, c[i].1 as f32 / 255.0));
+            let i = i as u32;
+            (i % self.width, i / self.width, a as f32 / 255.0, colour)
+        })
+    }
+
+    /*
+     * The memory taken up by the bitmap:
+     */
+    fn bytes(&self) -> usize {
+        self.alpha.len() + self.colour.as_ref().map_or(0, |c| c.len() * 4)
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    glyphs: HashMap<Key, Arc<Coverage>>,
+    /*
+     * The total size of the bitmaps in "glyphs":
+     */
+    bytes: usize,
+    /*
+     * The widest advance of any character used to draw numbers, keyed by the
+     * bits of the scale:
+     */
+    num_widths: HashMap<u32, f32>,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct GlyphCache {
+    inner: Mutex<Inner>,
+}
+
+impl GlyphCache {
+    pub(crate) fn glyph(
+        &self,
+        idx: usize,
+        font: &Font,
+        colour: Option<&ColourFont>,
+        c: char,
+        scale: f32,
+    ) -> Arc<Coverage> {
+        let key = (idx, c, scale.to_bits());
+
+        {
+            let mut i = self.inner.lock_or_recover();
+            if let Some(cov) = i.glyphs.get(&key).cloned() {
+                i.hits += 1;
+                return cov;
+            }
+            i.misses += 1;
+        }
+
+        /*
+         * Rasterise without holding the lock, so that we do not hold up
+         * anybody else who is drawing text.
+         */
+        let cov = Arc::new(Coverage::rasterise(font, colour, c, scale));
+
+        let mut i = self.inner.lock_or_recover();
+        if i.bytes + cov.bytes() > MAX_BYTES {
+            i.glyphs.clear();
+            i.bytes = 0;
+        }
+        if let Some(old) = i.glyphs.insert(key, Arc::clone(&cov)) {
+            /*
+             * Somebody else rasterised the same glyph while we were doing so.
+             */
+            i.bytes -= old.bytes();
+        }
+        i.bytes += cov.bytes();
+        cov
+    }
+
+    pub(crate) fn num_width<F: FnOnce() -> f32>(
+        &self,
+        scale: f32,
+        compute: F,
+    ) -> f32 {
+        if let Some(w) =
+            self.inner.lock_or_recover().num_widths.get(&scale.to_bits())
+        {
+            return *w;
+        }
+
+        let w = compute();
+
+        let mut i = self.inner.lock_or_recover();
+        if i.num_widths.len() >= MAX_WIDTHS {
+            i.num_widths.clear();
+        }
+        i.num_widths.insert(scale.to_bits(), w);
+        w
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut i = self.inner.lock_or_recover();
+        i.glyphs.clear();
+        i.bytes = 0;
+        i.num_widths.clear();
+    }
+
+    /*
+     * The number of lookups that found, and did not find, a glyph already in
+     * the cache:
+     */
+    pub(crate) fn stats(&self) -> (u64, u64) {
+        let i = self.inner.lock_or_recover();
+        (i.hits, i.misses)
+    }
+}
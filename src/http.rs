@@ -2,42 +2,828 @@
  * Copyright 2024 Oxide Computer Company
  */
 
-use std::{result::Result as SResult, sync::Arc, time::Duration};
+use std::{io::Cursor, result::Result as SResult, sync::Arc, time::Duration};
 
-use ::image::{imageops::FilterType, Rgb};
+use ::image::{imageops::FilterType, ImageOutputFormat, Rgb};
 use anyhow::{anyhow, bail, Result};
+use chrono::prelude::*;
 use dropshot::{
-    endpoint, HttpError, HttpResponseUpdatedNoContent, RequestContext,
-    TypedBody, UntypedBody,
+    endpoint, HttpError, HttpResponseOk, HttpResponseUpdatedNoContent, Query,
+    RequestContext, TypedBody, UntypedBody,
 };
-use hyper::StatusCode;
+use hyper::{header, Body, Response, StatusCode};
 use schemars::JsonSchema;
-use serde::Deserialize;
-use slog::info;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use tokio::sync::broadcast::error::RecvError;
 
-use crate::App;
+use crate::{config::Permission, utils::MutexExt, App, Content};
 
+/*
+ * A message is either plain "text", drawn in a single colour, or a list of
+ * "spans" each of which may be styled differently.
+ */
 #[derive(Deserialize, JsonSchema)]
-struct Message {
+pub(crate) struct Message {
+    rgb: [u8; 3],
+    text: Option<String>,
+    spans: Option<Vec<Span>>,
+    /*
+     * The height of each line of text, in pixels.  If not specified, the
+     * largest height at which the message fits on the display is used, up to
+     * "max_height" if that is specified.
+     */
+    height: Option<u32>,
+    max_height: Option<u32>,
+    flash: Option<u32>,
+    /*
+     * If the message will not fit on the display at the requested height,
+     * shrink it until it does rather than rejecting it:
+     */
+    fit: Option<bool>,
+    /*
+     * If specified, the message is drawn on a single line which scrolls
+     * across the display:
+     */
+    scroll: Option<Scroll>,
+    #[serde(flatten)]
+    lifetime: Lifetime,
+}
+
+/*
+ * A part of a message: either some text, or an icon.  The colour defaults to
+ * that of the message as a whole, and the size is a proportion of the height
+ * of the line (by default, 1).
+ */
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct Span {
+    text: Option<String>,
+    icon: Option<Icon>,
+    rgb: Option<[u8; 3]>,
+    size: Option<f32>,
+    bold: Option<bool>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum Icon {
+    Sun,
+    Moon,
+    Cloud,
+    Umbrella,
+    Star,
+    Coffee,
+    Aeroplane,
+    Heart,
+    Cake,
+    Pumpkin,
+    Tree,
+    Champagne,
+}
+
+impl Icon {
+    fn glyph(&self) -> char {
+        match self {
+            Icon::Sun => '\u{2600}',
+            Icon::Moon => '\u{263e}',
+            Icon::Cloud => '\u{2601}',
+            Icon::Umbrella => '\u{2602}',
+            Icon::Star => '\u{2605}',
+            Icon::Coffee => '\u{2615}',
+            Icon::Aeroplane => '\u{2708}',
+            Icon::Heart => '\u{2764}',
+            Icon::Cake => '\u{1f382}',
+            Icon::Pumpkin => '\u{1f383}',
+            Icon::Tree => '\u{1f384}',
+            Icon::Champagne => '\u{1f37e}',
+        }
+    }
+}
+
+impl Span {
+    fn styled(&self, rgb: Rgb<u8>) -> SResult<crate::StyledText, HttpError> {
+        let text = match (&self.text, &self.icon) {
+            (Some(text), None) => text.clone(),
+            (None, Some(icon)) => icon.glyph().to_string(),
+            _ => {
+                return Err(HttpError::for_bad_request(
+                    None,
+                    "each span must have exactly one of \"text\" and \
+                    \"icon\""
+                        .into(),
+                ));
+            }
+        };
+
+        let size = self.size.unwrap_or(1.0);
+        if !(size > 0.0 && size <= 1.0) {
+            return Err(HttpError::for_bad_request(
+                None,
+                "span size must be greater than 0 and at most 1".into(),
+            ));
+        }
+
+        let style = crate::Style {
+            rgb: self.rgb.map(Rgb).unwrap_or(rgb),
+            size,
+            bold: self.bold.unwrap_or(false),
+        };
+
+        Ok(crate::styled(&text, style))
+    }
+}
+
+impl Message {
+    pub(crate) async fn into_message(
+        self,
+        app: &App,
+        by: String,
+    ) -> SResult<crate::Message, HttpError> {
+        if self.flash == Some(0) {
+            return Err(HttpError::for_bad_request(
+                None,
+                "flash period must be greater than zero".into(),
+            ));
+        }
+
+        /*
+         * The render loop sleeps for the flash period between frames, so the
+         * period must be short enough that the watchdog does not think the
+         * render loop has stopped:
+         */
+        let max_flash = app.config.watchdog.stale_secs.saturating_mul(1000) / 2;
+        if self.flash.is_some_and(|f| u64::from(f) >= max_flash) {
+            return Err(HttpError::for_bad_request(
+                None,
+                format!("flash period must be less than {max_flash} ms"),
+            ));
+        }
+
+        if self.height == Some(0) || self.max_height == Some(0) {
+            return Err(HttpError::for_bad_request(
+                None,
+                "height must be greater than zero".into(),
+            ));
+        }
+
+        if self.height.is_some() && self.max_height.is_some() {
+            return Err(HttpError::for_bad_request(
+                None,
+                "specify at most one of \"height\" and \"max_height\"".into(),
+            ));
+        }
+
+        let rgb = Rgb(self.rgb);
+        let text = match (&self.text, &self.spans) {
+            (Some(text), None) => crate::styled(text, crate::Style::plain(rgb)),
+            (None, Some(spans)) => spans
+                .iter()
+                .map(|span| span.styled(rgb))
+                .collect::<SResult<Vec<_>, _>>()?
+                .concat(),
+            _ => {
+                return Err(HttpError::for_bad_request(
+                    None,
+                    "specify exactly one of \"text\" and \"spans\"".into(),
+                ));
+            }
+        };
+
+        let size = app.wait_for_size().await;
+        let (height, lines, scroll) = if let Some(scroll) = &self.scroll {
+            let (height, line, scroll) = self.marquee(size, scroll, &text)?;
+            (height, vec![line], Some(scroll))
+        } else {
+            let (height, lines) = self.wrap(app, size, &text)?;
+            (height, lines, None)
+        };
+
+        /*
+         * Lines are wrapped in the order in which the text is written, but
+         * drawn from left to right:
+         */
+        let lines = lines.iter().map(|l| crate::visual_order(l)).collect();
+
+        let posted = self.lifetime.posted(by)?;
+
+        Ok(crate::Message {
+            rgb,
+            text: text.iter().map(|(c, _)| c).collect(),
+            lines,
+            height,
+            flash: self.flash.map(|msec| Duration::from_millis(msec.into())),
+            scroll,
+            posted,
+        })
+    }
+
+    /*
+     * Wrap the text onto as many lines as it needs, and choose a height at
+     * which all of those lines fit on the display.
+     */
+    fn wrap(
+        &self,
+        app: &App,
+        (dw, dh): (u32, u32),
+        text: &[(char, crate::Style)],
+    ) -> SResult<(u32, Vec<crate::StyledText>), HttpError> {
+        let fonts = app.fonts();
+        let fit = |max: u32| {
+            crate::fit_text(text, &fonts, max.min(dh), dw, dh).ok_or_else(
+                || {
+                    HttpError::for_bad_request(
+                        None,
+                        format!(
+                            "message will not fit on the display \
+                            ({dw} x {dh} pixels) at any height"
+                        ),
+                    )
+                },
+            )
+        };
+
+        match self.height {
+            None => fit(self.max_height.unwrap_or(dh)),
+            Some(h) => {
+                let lines = crate::wrap_text(text, &fonts, h, dw)
+                    .filter(|l| l.len() as u64 * h as u64 <= dh as u64);
+
+                match lines {
+                    Some(lines) => Ok((h, lines)),
+                    None if self.fit.unwrap_or(false) => fit(h),
+                    None => {
+                        let (max, _) = fit(h)?;
+                        Err(HttpError::for_bad_request(
+                            None,
+                            format!(
+                                "message will not fit on the display \
+                                ({dw} x {dh} pixels) at height {h}; use a \
+                                height of at most {max}, omit the height, or \
+                                set \"fit\": true"
+                            ),
+                        ))
+                    }
+                }
+            }
+        }
+    }
+
+    /*
+     * A scrolling message is drawn on a single line, at a height which fits
+     * in the band of the display through which it scrolls; it may be as wide
+     * as it likes.
+     */
+    fn marquee(
+        &self,
+        (_, dh): (u32, u32),
+        scroll: &Scroll,
+        text: &[(char, crate::Style)],
+    ) -> SResult<(u32, crate::StyledText, crate::Scroll), HttpError> {
+        if self.flash.is_some() {
+            return Err(HttpError::for_bad_request(
+                None,
+                "a scrolling message cannot also flash".into(),
+            ));
+        }
+
+        let speed = scroll.speed.unwrap_or(DEFAULT_SCROLL_SPEED);
+        if speed == 0 {
+            return Err(HttpError::for_bad_request(
+                None,
+                "scroll speed must be greater than zero".into(),
+            ));
+        }
+
+        let band = scroll.band.unwrap_or(Band::Full);
+        let room = match band {
+            Band::Full => dh,
+            Band::Bottom => crate::ticker_band(dh).1,
+        };
+
+        let height = match self.height {
+            Some(h) if h > room && !self.fit.unwrap_or(false) => {
+                return Err(HttpError::for_bad_request(
+                    None,
+                    format!(
+                        "height {h} exceeds the height of the band ({room} \
+                        pixels); use a height of at most {room}, omit the \
+                        height, or set \"fit\": true"
+                    ),
+                ));
+            }
+            Some(h) => h.min(room),
+            None => self.max_height.unwrap_or(room).min(room),
+        };
+
+        /*
+         * There is only one line, so any newlines become spaces:
+         */
+        let line = text
+            .iter()
+            .map(|&(c, style)| (if c == '\n' { ' ' } else { c }, style))
+            .collect();
+
+        Ok((height, line, crate::Scroll { speed, band }))
+    }
+}
+
+/*
+ * In pixels per second:
+ */
+const DEFAULT_SCROLL_SPEED: u32 = 400;
+
+/*
+ * Where on the display a scrolling message should appear: either across the
+ * whole display in place of the clock, or in a band along the bottom of the
+ * display beneath the time.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Band {
+    Full,
+    Bottom,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct Scroll {
+    /*
+     * In pixels per second:
+     */
+    speed: Option<u32>,
+    band: Option<Band>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct Timer {
+    secs: u32,
+    label: Option<String>,
+    rgb: Option<[u8; 3]>,
+    #[serde(flatten)]
+    lifetime: Lifetime,
+}
+
+impl Timer {
+    pub(crate) fn into_timer(
+        self,
+        by: String,
+    ) -> SResult<crate::Timer, HttpError> {
+        let posted = self.lifetime.posted(by)?;
+
+        Ok(crate::Timer {
+            ends: posted.at + chrono::Duration::seconds(self.secs.into()),
+            label: self.label,
+            rgb: self.rgb.map(Rgb),
+            posted,
+        })
+    }
+}
+
+/*
+ * Override the power schedule, keeping the display lit ("on") or blank
+ * ("off") whatever the time, until the override expires or the schedule is
+ * restored ("auto").
+ */
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct Power {
+    mode: PowerMode,
+    #[serde(flatten)]
+    lifetime: Lifetime,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PowerMode {
+    Auto,
+    On,
+    Off,
+}
+
+impl Power {
+    pub(crate) fn into_power(
+        self,
+        by: String,
+    ) -> SResult<Option<crate::Power>, HttpError> {
+        let posted = self.lifetime.posted(by)?;
+
+        Ok(match self.mode {
+            PowerMode::Auto => None,
+            PowerMode::On => Some(crate::Power { on: true, posted }),
+            PowerMode::Off => Some(crate::Power { on: false, posted }),
+        })
+    }
+}
+
+/*
+ * Posted content may be given a limited lifetime, either as a number of
+ * seconds from now or as an absolute time.  Once that time passes, the content
+ * is discarded and the clock is displayed again.
+ */
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct Lifetime {
+    ttl: Option<u32>,
+    until: Option<DateTime<Utc>>,
+}
+
+/*
+ * Options for posting an image, given as query parameters.  (These cannot
+ * include a flattened "Lifetime", as numbers in flattened structures cannot be
+ * parsed from a query string.)
+ */
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct ImageOptions {
+    ttl: Option<u32>,
+    until: Option<DateTime<Utc>>,
+    /*
+     * If specified, the image becomes a background and the clock is drawn on
+     * top of it, either across the whole display or in one half of it:
+     */
+    clock: Option<ClockPosition>,
+    /*
+     * Where to put the image, if it does not fill the display.  By default,
+     * the image is centred, or put in the half of the display not used by
+     * the clock.
+     */
+    align: Option<ImageAlign>,
+    /*
+     * Scale the image to cover the whole display, cropping it if need be,
+     * rather than to fit within the display:
+     */
+    cover: Option<bool>,
+    /*
+     * How to keep the clock legible against the image; by default, with a
+     * drop shadow:
+     */
+    legibility: Option<Legibility>,
+}
+
+impl ImageOptions {
+    pub(crate) fn lifetime(&self) -> Lifetime {
+        Lifetime { ttl: self.ttl, until: self.until }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ClockPosition {
+    Full,
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ImageAlign {
+    Left,
+    Centre,
+    Right,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Legibility {
+    Plain,
+    Shadow,
+    Outline,
+}
+
+impl Lifetime {
+    pub(crate) fn posted(
+        &self,
+        by: String,
+    ) -> SResult<crate::Posted, HttpError> {
+        let now = Utc::now();
+
+        let expires = match (self.ttl, self.until) {
+            (Some(_), Some(_)) => {
+                return Err(HttpError::for_bad_request(
+                    None,
+                    "specify at most one of \"ttl\" and \"until\"".into(),
+                ));
+            }
+            (Some(ttl), None) => {
+                Some(now + chrono::Duration::seconds(ttl.into()))
+            }
+            (None, Some(until)) => Some(until),
+            (None, None) => None,
+        };
+
+        if expires.is_some_and(|t| t <= now) {
+            return Err(HttpError::for_bad_request(
+                None,
+                "expiry time must be in the future".into(),
+            ));
+        }
+
+        Ok(crate::Posted::new(now, by, expires))
+    }
+}
+
+#[derive(Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    Clock,
+    Message,
+    Image,
+    Timer,
+}
+
+impl Mode {
+    const ALL: &'static [&'static str] =
+        &["clock", "message", "image", "timer"];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Mode::Clock => "clock",
+            Mode::Message => "message",
+            Mode::Image => "image",
+            Mode::Timer => "timer",
+        }
+    }
+}
+
+#[derive(Clone, Serialize, JsonSchema)]
+struct PostedState {
+    set_at: DateTime<Utc>,
+    set_by: String,
+    expires: Option<DateTime<Utc>>,
+    remaining_secs: Option<i64>,
+}
+
+impl PostedState {
+    fn new(posted: &crate::Posted, now: DateTime<Utc>) -> PostedState {
+        PostedState {
+            set_at: posted.at,
+            set_by: posted.by.clone(),
+            expires: posted.expires,
+            remaining_secs: posted
+                .expires
+                .map(|t| (t - now).num_seconds().max(0)),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, JsonSchema)]
+struct MessageState {
     rgb: [u8; 3],
     text: String,
     height: u32,
     flash: Option<u32>,
+    scroll: Option<Scroll>,
+    #[serde(flatten)]
+    posted: PostedState,
 }
 
-impl From<Message> for crate::Message {
-    fn from(value: Message) -> Self {
-        crate::Message {
-            rgb: Rgb([value.rgb[0], value.rgb[1], value.rgb[2]]),
-            text: value.text,
-            height: value.height,
-            flash: value
-                .flash
-                .map(|msec| Duration::from_millis(msec.try_into().unwrap())),
-        }
+#[derive(Clone, Serialize, JsonSchema)]
+struct ImageState {
+    width: u32,
+    height: u32,
+    clock: Option<ClockPosition>,
+    align: ImageAlign,
+    #[serde(flatten)]
+    posted: PostedState,
+}
+
+#[derive(Clone, Serialize, JsonSchema)]
+struct TimerState {
+    ends: DateTime<Utc>,
+    label: Option<String>,
+    rgb: [u8; 3],
+    #[serde(flatten)]
+    posted: PostedState,
+}
+
+#[derive(Clone, Serialize, JsonSchema)]
+struct DisplayState {
+    width: u32,
+    height: u32,
+    /*
+     * The name of the theme currently in use:
+     */
+    theme: String,
+    /*
+     * Whether the display is blank, either because of the power schedule or
+     * because it has been turned off:
+     */
+    blanked: bool,
+    /*
+     * Any override of the power schedule:
+     */
+    power: Option<PowerState>,
+}
+
+#[derive(Clone, Serialize, JsonSchema)]
+struct PowerState {
+    mode: PowerMode,
+    #[serde(flatten)]
+    posted: PostedState,
+}
+
+#[derive(Clone, Serialize, JsonSchema)]
+pub(crate) struct State {
+    mode: Mode,
+    display: DisplayState,
+    message: Option<MessageState>,
+    image: Option<ImageState>,
+    timer: Option<TimerState>,
+}
+
+/*
+ * Compare two secrets in time that depends only on their lengths, so as not to
+ * reveal how much of a guessed token is correct.
+ */
+fn secrets_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn unauthorised(status: StatusCode, msg: &str) -> HttpError {
+    HttpError::for_client_error(None, status, msg.to_string())
+}
+
+/*
+ * Every endpoint calls this first, so that "GET /metrics" can report how many
+ * requests each endpoint has handled.
+ */
+fn count(rc: &RequestContext<Arc<App>>) {
+    rc.context()
+        .metrics
+        .request(rc.request.method().as_str(), rc.request.uri().path());
+}
+
+/*
+ * Check that a request carries a token with the required permission, if the
+ * configuration demands one.  Returns a description of the requester, which is
+ * recorded as the author of anything they post.
+ */
+fn authorise(
+    rc: &RequestContext<Arc<App>>,
+    perm: Permission,
+) -> SResult<String, HttpError> {
+    let auth = &rc.context().config.auth;
+    let log = &rc.log;
+    let remote = rc.request.remote_addr();
+
+    if auth.tokens.is_empty()
+        || (perm == Permission::Read && !auth.protect_reads)
+    {
+        return Ok(remote.to_string());
+    }
+
+    let Some(secret) = rc
+        .request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+    else {
+        warn!(log, "request without a token from {remote} ({perm:?})");
+        return Err(unauthorised(
+            StatusCode::UNAUTHORIZED,
+            "a bearer token is required",
+        ));
+    };
+
+    let Some(token) = auth
+        .tokens
+        .iter()
+        .find(|t| secrets_equal(t.secret.as_bytes(), secret.as_bytes()))
+    else {
+        warn!(log, "request with invalid token from {remote} ({perm:?})");
+        return Err(unauthorised(StatusCode::UNAUTHORIZED, "invalid token"));
+    };
+
+    if !token.permissions.contains(&perm) {
+        warn!(
+            log,
+            "token {:?} from {remote} lacks permission {perm:?}", token.name
+        );
+        return Err(unauthorised(
+            StatusCode::FORBIDDEN,
+            "token does not permit this operation",
+        ));
+    }
+
+    Ok(format!("{} ({remote})", token.name))
+}
+
+#[endpoint {
+    method = GET,
+    path = "/state",
+}]
+async fn state(
+    rc: RequestContext<Arc<App>>,
+) -> SResult<HttpResponseOk<State>, HttpError> {
+    count(&rc);
+    authorise(&rc, Permission::Read)?;
+
+    Ok(HttpResponseOk(current_state(rc.context())))
+}
+
+pub(crate) fn current_state(app: &App) -> State {
+    let now = Utc::now();
+
+    let expired = app.expire(now);
+    let c = app.content();
+    let (width, height) = app.size().unwrap_or_default();
+
+    let message = c.msg.as_ref().map(|m| MessageState {
+        rgb: m.rgb.0,
+        text: m.text.clone(),
+        height: m.height,
+        flash: m.flash.map(|d| d.as_millis().try_into().unwrap_or(u32::MAX)),
+        scroll: m
+            .scroll
+            .map(|s| Scroll { speed: Some(s.speed), band: Some(s.band) }),
+        posted: PostedState::new(&m.posted, now),
+    });
+
+    let image = c.image.as_ref().map(|im| ImageState {
+        width: im.img.width(),
+        height: im.img.height(),
+        clock: im.clock,
+        align: im.align,
+        posted: PostedState::new(&im.posted, now),
+    });
+
+    let power = c.power.as_ref().map(|p| PowerState {
+        mode: if p.on { PowerMode::On } else { PowerMode::Off },
+        posted: PostedState::new(&p.posted, now),
+    });
+
+    let timer = c.timer.as_ref().map(|t| TimerState {
+        ends: t.ends,
+        label: t.label.clone(),
+        rgb: t.rgb.unwrap_or(Rgb(app.theme(now).time)).0,
+        posted: PostedState::new(&t.posted, now),
+    });
+
+    /*
+     * An image, if present, is displayed in preference to a message, which is
+     * in turn displayed in preference to a timer:
+     */
+    let mode = if image.is_some() {
+        Mode::Image
+    } else if message.is_some() {
+        Mode::Message
+    } else if timer.is_some() {
+        Mode::Timer
+    } else {
+        Mode::Clock
+    };
+
+    let state = State {
+        mode,
+        display: DisplayState {
+            width,
+            height,
+            theme: app.theme_name(now).to_string(),
+            blanked: app.blanked(now, &c),
+            power,
+        },
+        message,
+        image,
+        timer,
+    };
+
+    if expired {
+        app.event(Event::State(state.clone()));
+    }
+
+    state
+}
+
+#[derive(Clone)]
+pub(crate) enum Event {
+    State(State),
+    Error(String),
+}
+
+impl Event {
+    fn to_sse(&self) -> Result<String> {
+        let (name, data) = match self {
+            Event::State(state) => ("state", serde_json::to_string(state)?),
+            Event::Error(message) => (
+                "error",
+                serde_json::to_string(&serde_json::json!({
+                    "message": message,
+                }))?,
+            ),
+        };
+
+        Ok(format!("event: {name}\ndata: {data}\n\n"))
     }
 }
 
+/*
+ * Let anybody watching "GET /events" know that the display content has
+ * changed.
+ */
+pub(crate) fn notify(app: &App) {
+    let state = current_state(app);
+    app.event(Event::State(state));
+}
+
 #[endpoint {
     method = POST,
     path = "/clear",
@@ -45,16 +831,176 @@ impl From<Message> for crate::Message {
 async fn clear(
     rc: RequestContext<Arc<App>>,
 ) -> SResult<HttpResponseUpdatedNoContent, HttpError> {
+    count(&rc);
+    authorise(&rc, Permission::Clear)?;
+
+    clear_all(rc.context());
+
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+pub(crate) fn clear_all(app: &App) {
+    /*
+     * Clearing the display does not undo any override of the power schedule:
+     */
+    app.update(|c| {
+        *c = Content { power: c.power.take(), ..Default::default() }
+    });
+    notify(app);
+}
+
+pub(crate) fn post_power(app: &App, power: Option<crate::Power>) {
+    app.update(|c| c.power = power.map(Arc::new));
+    notify(app);
+}
+
+pub(crate) fn post_message(app: &App, msg: crate::Message) {
+    app.update(|c| c.msg = Some(Arc::new(msg)));
+    notify(app);
+}
+
+pub(crate) fn post_timer(app: &App, timer: crate::Timer) {
+    app.update(|c| c.timer = Some(Arc::new(timer)));
+    notify(app);
+}
+
+#[endpoint {
+    method = POST,
+    path = "/timer",
+}]
+async fn timer(
+    rc: RequestContext<Arc<App>>,
+    body: TypedBody<Timer>,
+) -> SResult<HttpResponseUpdatedNoContent, HttpError> {
+    count(&rc);
+    let by = authorise(&rc, Permission::Timer)?;
     let app = rc.context();
+    let b = body.into_inner();
 
-    let mut i = app.inner.lock().unwrap();
+    let timer = b.into_timer(by)?;
 
-    i.msg = None;
-    i.image = None;
+    post_timer(app, timer);
 
     Ok(HttpResponseUpdatedNoContent())
 }
 
+#[endpoint {
+    method = POST,
+    path = "/power",
+}]
+async fn power(
+    rc: RequestContext<Arc<App>>,
+    body: TypedBody<Power>,
+) -> SResult<HttpResponseUpdatedNoContent, HttpError> {
+    count(&rc);
+    let by = authorise(&rc, Permission::Power)?;
+    let app = rc.context();
+    let b = body.into_inner();
+
+    let power = b.into_power(by)?;
+
+    post_power(app, power);
+
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+#[endpoint {
+    method = GET,
+    path = "/screenshot",
+}]
+async fn screenshot(
+    rc: RequestContext<Arc<App>>,
+) -> SResult<Response<Body>, HttpError> {
+    count(&rc);
+    authorise(&rc, Permission::Read)?;
+
+    let png = screenshot_png(rc.context())
+        .map_err(|e| HttpError::for_internal_error(format!("png: {e}")))?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/png")
+        .body(png.into())?)
+}
+
+/*
+ * Encode the most recent frame sent to the display as a PNG file.
+ */
+pub(crate) fn screenshot_png(app: &App) -> Result<Vec<u8>> {
+    let mut png = Cursor::new(Vec::new());
+    app.frame.lock_or_recover().write_to(&mut png, ImageOutputFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+/*
+ * A stream of server-sent events, with a "state" event (containing the same
+ * document as "GET /state") whenever the display content changes, and an
+ * "error" event whenever the display device reports a problem.  The current
+ * state is sent immediately on connection.
+ */
+#[endpoint {
+    method = GET,
+    path = "/events",
+}]
+async fn events(
+    rc: RequestContext<Arc<App>>,
+) -> SResult<Response<Body>, HttpError> {
+    count(&rc);
+    authorise(&rc, Permission::Read)?;
+    let app = Arc::clone(rc.context());
+
+    let mut rx = app.events.subscribe();
+    let (mut tx, body) = Body::channel();
+
+    tokio::task::spawn(async move {
+        let mut ev = Event::State(current_state(&app));
+        let mut keepalive = tokio::time::interval(Duration::from_secs(15));
+
+        loop {
+            let Ok(data) = ev.to_sse() else {
+                break;
+            };
+            if tx.send_data(data.into()).await.is_err() {
+                /*
+                 * The client has gone away.
+                 */
+                break;
+            }
+
+            ev = loop {
+                tokio::select! {
+                    res = rx.recv() => match res {
+                        Ok(ev) => break ev,
+                        Err(RecvError::Lagged(_)) => {
+                            /*
+                             * We have missed some events.  The current state
+                             * supersedes any we missed.
+                             */
+                            break Event::State(current_state(&app));
+                        }
+                        Err(RecvError::Closed) => return,
+                    },
+                    _ = keepalive.tick() => {
+                        /*
+                         * Send a comment from time to time, so that we notice
+                         * if the client goes away.
+                         */
+                        if tx.send_data(":\n\n".into()).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            };
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)?)
+}
+
 #[endpoint {
     method = POST,
     path = "/message",
@@ -63,12 +1009,14 @@ async fn message(
     rc: RequestContext<Arc<App>>,
     body: TypedBody<Message>,
 ) -> SResult<HttpResponseUpdatedNoContent, HttpError> {
+    count(&rc);
+    let by = authorise(&rc, Permission::Message)?;
     let app = rc.context();
     let b = body.into_inner();
 
-    let mut i = app.inner.lock().unwrap();
+    let msg = b.into_message(app, by).await?;
 
-    i.msg = Some(b.into());
+    post_message(app, msg);
 
     Ok(HttpResponseUpdatedNoContent())
 }
@@ -79,14 +1027,30 @@ async fn message(
 }]
 async fn image(
     rc: RequestContext<Arc<App>>,
+    query: Query<ImageOptions>,
     body: UntypedBody,
 ) -> SResult<HttpResponseUpdatedNoContent, HttpError> {
-    let app = rc.context();
-    let log = &rc.log;
+    count(&rc);
+    let by = authorise(&rc, Permission::Image)?;
+    let options = query.into_inner();
+    let posted = options.lifetime().posted(by)?;
+
+    post_image(rc.context(), &rc.log, body.as_bytes(), &options, posted)
+        .await?;
 
-    match ::image::load_from_memory(body.as_bytes()) {
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+pub(crate) async fn post_image(
+    app: &App,
+    log: &Logger,
+    data: &[u8],
+    options: &ImageOptions,
+    posted: crate::Posted,
+) -> SResult<(), HttpError> {
+    let res = match ::image::load_from_memory(data) {
         Ok(img) => {
-            let mut i = app.inner.lock().unwrap();
+            let (width, height) = app.wait_for_size().await;
 
             info!(
                 log,
@@ -95,42 +1059,306 @@ async fn image(
                 img.height()
             );
 
-            let img =
-                img.resize(i.width, i.height, FilterType::Gaussian).to_rgb8();
+            let img = if options.cover.unwrap_or(false) {
+                img.resize_to_fill(width, height, FilterType::Gaussian)
+            } else {
+                img.resize(width, height, FilterType::Gaussian)
+            }
+            .to_rgb8();
 
             info!(log, "resized image = {} x {}", img.width(), img.height());
 
-            i.image = Some(img);
+            let align = options.align.unwrap_or(match options.clock {
+                Some(ClockPosition::Left) => ImageAlign::Right,
+                Some(ClockPosition::Right) => ImageAlign::Left,
+                _ => ImageAlign::Centre,
+            });
+
+            let image = crate::Image {
+                img,
+                clock: options.clock,
+                align,
+                legibility: options.legibility.unwrap_or(Legibility::Shadow),
+                posted,
+            };
+
+            app.update(|c| c.image = Some(Arc::new(image)));
 
-            Ok(HttpResponseUpdatedNoContent())
+            Ok(())
         }
         Err(e) => Err(HttpError::for_client_error(
             None,
             StatusCode::BAD_REQUEST,
             format!("image problem: {e}"),
         )),
+    };
+
+    if res.is_ok() {
+        notify(app);
     }
+
+    res
 }
 
-pub(crate) async fn server(
-    app: Arc<App>,
-    bind_address: std::net::SocketAddr,
-) -> Result<()> {
-    let cd = dropshot::ConfigDropshot {
-        bind_address,
-        request_body_max_bytes: 32 * 1024 * 1024,
-        ..Default::default()
-    };
+/*
+ * A font to add to the font stack, for use in messages.  The font applies
+ * first to the characters in "ranges", a comma-separated list of hexadecimal
+ * code points or ranges of code points (e.g., "0590-05FF,FB1D-FB4F"); it is
+ * also used for any other character that it can draw and the built-in fonts
+ * cannot.  A font uploaded with the same name as an earlier one replaces it;
+ * the built-in fonts ("unifont", "unifont_upper", and "Domine") may not be
+ * replaced.
+ */
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct FontOptions {
+    name: String,
+    ranges: Option<String>,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/font",
+}]
+async fn font(
+    rc: RequestContext<Arc<App>>,
+    query: Query<FontOptions>,
+    body: UntypedBody,
+) -> SResult<HttpResponseUpdatedNoContent, HttpError> {
+    count(&rc);
+    authorise(&rc, Permission::Font)?;
+    let options = query.into_inner();
+
+    post_font(rc.context(), &rc.log, body.as_bytes().to_vec(), &options)?;
+
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+pub(crate) fn post_font(
+    app: &App,
+    log: &Logger,
+    data: Vec<u8>,
+    options: &FontOptions,
+) -> SResult<(), HttpError> {
+    let ranges = options
+        .ranges
+        .iter()
+        .flat_map(|r| r.split(','))
+        .filter(|r| !r.trim().is_empty())
+        .map(crate::parse_range)
+        .collect::<Result<Vec<_>>>()
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+
+    let fse = crate::load_font_owned(
+        &options.name,
+        crate::FontSource::Uploaded,
+        data,
+        ranges,
+    )
+    .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+
+    app.add_font(fse)
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+    info!(log, "added font {:?}", options.name);
+
+    Ok(())
+}
+
+#[endpoint {
+    method = GET,
+    path = "/openapi.json",
+    unpublished = true,
+}]
+async fn openapi(
+    rc: RequestContext<Arc<App>>,
+) -> SResult<HttpResponseOk<serde_json::Value>, HttpError> {
+    count(&rc);
+    authorise(&rc, Permission::Read)?;
+
+    spec()
+        .map(HttpResponseOk)
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Health {
+    last_paint_secs: f64,
+}
+
+/*
+ * Succeeds only if the render loop has recently painted a frame.
+ */
+#[endpoint {
+    method = GET,
+    path = "/health",
+}]
+async fn health(
+    rc: RequestContext<Arc<App>>,
+) -> SResult<HttpResponseOk<Health>, HttpError> {
+    count(&rc);
+    authorise(&rc, Permission::Read)?;
+    let app = rc.context();
+
+    let age = app.last_paint.lock_or_recover().elapsed();
+
+    if age > Duration::from_secs(app.config.watchdog.stale_secs) {
+        return Err(HttpError::for_unavail(
+            None,
+            format!("no frame painted for {} seconds", age.as_secs()),
+        ));
+    }
+
+    Ok(HttpResponseOk(Health { last_paint_secs: age.as_secs_f64() }))
+}
+
+/*
+ * Metrics in the Prometheus text format.
+ */
+#[endpoint {
+    method = GET,
+    path = "/metrics",
+}]
+async fn metrics(
+    rc: RequestContext<Arc<App>>,
+) -> SResult<Response<Body>, HttpError> {
+    count(&rc);
+    authorise(&rc, Permission::Read)?;
+    let app = rc.context();
+
+    let mode = current_state(app).mode;
+    let text = app.metrics.render(mode.name(), Mode::ALL);
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(text.into())?)
+}
 
+fn api() -> dropshot::ApiDescription<Arc<App>> {
     let mut api = dropshot::ApiDescription::new();
     api.register(message).unwrap();
     api.register(clear).unwrap();
     api.register(image).unwrap();
+    api.register(font).unwrap();
+    api.register(state).unwrap();
+    api.register(timer).unwrap();
+    api.register(power).unwrap();
+    api.register(screenshot).unwrap();
+    api.register(events).unwrap();
+    api.register(metrics).unwrap();
+    api.register(health).unwrap();
+    api.register(openapi).unwrap();
+    api
+}
+
+/*
+ * Produce the OpenAPI document for the server.  A copy of this document is
+ * checked in as "openapi/clock.json", from which the client crate is
+ * generated; it should be regenerated with "cargo run -- openapi" whenever the
+ * API changes.
+ */
+pub(crate) fn spec() -> Result<serde_json::Value> {
+    Ok(api().openapi("Oxide Office Clock", env!("CARGO_PKG_VERSION")).json()?)
+}
+
+pub(crate) async fn server(app: Arc<App>) -> Result<()> {
+    let s = start(app)?;
+
+    s.await.map_err(|e| anyhow!("HTTP server failure: {}", e))?;
+    bail!("HTTP server exited unexpectedly");
+}
+
+/*
+ * Start the server in the background, returning a handle from which the
+ * address it is listening on may be obtained.
+ */
+fn start(app: Arc<App>) -> Result<dropshot::HttpServer<Arc<App>>> {
+    let cd = dropshot::ConfigDropshot {
+        bind_address: app.config.http.listen,
+        request_body_max_bytes: 32 * 1024 * 1024,
+        ..Default::default()
+    };
+
+    let api = api();
+
+    let tls = app.config.tls.as_ref().map(|tls| dropshot::ConfigTls::AsFile {
+        cert_file: tls.cert_file.clone(),
+        key_file: tls.key_file.clone(),
+    });
 
     let log = app.log.clone();
-    let s = dropshot::HttpServerStarter::new(&cd, api, app, &log)
+    let s = dropshot::HttpServerStarter::new_with_tls(&cd, api, app, &log, tls)
         .map_err(|e| anyhow!("server starter error: {:?}", e))?;
 
-    s.start().await.map_err(|e| anyhow!("HTTP server failure: {}", e))?;
-    bail!("HTTP server exited unexpectedly");
+    Ok(s.start())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /*
+     * The client is generated from the checked-in copy of the OpenAPI
+     * document, so it must describe the API that the server registers.
+     */
+    #[test]
+    fn spec_matches_checked_in_document() {
+        let checked_in: serde_json::Value =
+            serde_json::from_str(include_str!("../openapi/clock.json"))
+                .unwrap();
+
+        assert!(
+            spec().unwrap() == checked_in,
+            "openapi/clock.json is out of date; regenerate it with \
+            \"cargo run -- openapi > openapi/clock.json\""
+        );
+    }
+
+    /*
+     * With a certificate and key configured, the server speaks HTTPS to
+     * clients that trust the certificate, and nothing else.
+     */
+    #[tokio::test]
+    async fn tls() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])
+            .unwrap();
+        let pem = cert.serialize_pem().unwrap();
+
+        let dir = std::env::temp_dir()
+            .join(format!("clock-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_file = dir.join("cert.pem");
+        let key_file = dir.join("key.pem");
+        std::fs::write(&cert_file, &pem).unwrap();
+        std::fs::write(&key_file, cert.serialize_private_key_pem()).unwrap();
+
+        let config = crate::config::Config {
+            http: crate::config::Http {
+                enabled: true,
+                listen: "127.0.0.1:0".parse().unwrap(),
+            },
+            tls: Some(crate::config::Tls { cert_file, key_file }),
+            ..Default::default()
+        };
+        let s = start(Arc::new(App::new(config).unwrap())).unwrap();
+        let addr = s.local_addr();
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(pem.as_bytes()).unwrap(),
+            )
+            .resolve("localhost", addr)
+            .build()
+            .unwrap();
+        let res = client
+            .get(format!("https://localhost:{}/state", addr.port()))
+            .send()
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+
+        let res = reqwest::get(format!("http://{addr}/state")).await;
+        assert!(res.is_err(), "plain HTTP request succeeded");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
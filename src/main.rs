@@ -4,67 +4,468 @@
 
 use std::{
     iter::once,
-    net::{Ipv4Addr, SocketAddr},
-    ops::RangeInclusive,
-    sync::{Arc, Mutex},
+    ops::{Range, RangeInclusive},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
     time::{Duration, Instant},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use chrono::prelude::*;
 use image::{GenericImage, ImageBuffer, Rgb, RgbImage};
-use rusttype::{point, Font, Scale};
-
+use rusttype::{Font, GlyphId, Scale};
+use transition::Scene;
+use unicode_bidi::BidiInfo;
+
+mod bench;
+mod burnin;
+mod colour;
+mod config;
+mod control;
 #[cfg(target_os = "illumos")]
 mod ctf;
 #[cfg(target_os = "illumos")]
 mod fb;
+mod glyphcache;
 mod http;
 #[cfg(target_os = "illumos")]
 mod kvm;
+mod metrics;
+mod transition;
 mod utils;
 #[cfg(target_os = "linux")]
 mod x11;
 
-use slog::Logger;
+use slog::{error, info, Logger};
+use tokio::sync::{broadcast, watch};
+use utils::MutexExt;
+
+/*
+ * Bookkeeping for content furnished via the HTTP API: when it arrived, who
+ * sent it, and when (if ever) it should be removed again.
+ */
+struct Posted {
+    /*
+     * A number unique to each thing posted, so that the render loop can tell
+     * when one message (say) has been replaced by another:
+     */
+    id: u64,
+    at: DateTime<Utc>,
+    by: String,
+    expires: Option<DateTime<Utc>>,
+}
+
+impl Posted {
+    fn new(
+        at: DateTime<Utc>,
+        by: String,
+        expires: Option<DateTime<Utc>>,
+    ) -> Posted {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        Posted { id, at, by, expires }
+    }
+}
 
 struct Message {
     rgb: Rgb<u8>,
     text: String,
+    /*
+     * The text, styled and wrapped to fit the display:
+     */
+    lines: Vec<StyledText>,
     height: u32,
     flash: Option<Duration>,
+    scroll: Option<Scroll>,
+    posted: Posted,
 }
 
-struct Inner {
-    msg: Option<Message>,
-    image: Option<ImageBuffer<Rgb<u8>, Vec<u8>>>,
-    width: u32,
-    height: u32,
+/*
+ * A message that scrolls across the display, rather than standing still:
+ */
+#[derive(Clone, Copy)]
+struct Scroll {
+    /*
+     * In pixels per second:
+     */
+    speed: u32,
+    band: http::Band,
+}
+
+struct Image {
+    img: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    /*
+     * If the image is to be drawn behind the clock, where to put the clock:
+     */
+    clock: Option<http::ClockPosition>,
+    align: http::ImageAlign,
+    legibility: http::Legibility,
+    posted: Posted,
+}
+
+/*
+ * A countdown to some time in the future, shown in place of the clock.
+ */
+struct Timer {
+    ends: DateTime<Utc>,
+    label: Option<String>,
+    /*
+     * If not specified, the timer is drawn in the same colour as the clock:
+     */
+    rgb: Option<Rgb<u8>>,
+    posted: Posted,
+}
+
+/*
+ * An override of the power schedule, lighting or blanking the display whatever
+ * the time:
+ */
+struct Power {
+    on: bool,
+    posted: Posted,
+}
+
+/*
+ * A snapshot of the content furnished for display.  Each item is shared,
+ * rather than copied, between snapshots.
+ */
+#[derive(Clone, Default)]
+struct Content {
+    msg: Option<Arc<Message>>,
+    image: Option<Arc<Image>>,
+    timer: Option<Arc<Timer>>,
+    power: Option<Arc<Power>>,
+}
+
+impl Content {
+    /*
+     * Discard any message, image, or timer that has outlived its requested
+     * lifetime, so that the clock comes back without anybody having to clear
+     * it.
+     */
+    fn expire(&mut self, log: &Logger, now: DateTime<Utc>) -> bool {
+        let mut expired = false;
+
+        if self
+            .msg
+            .as_ref()
+            .and_then(|m| m.posted.expires)
+            .is_some_and(|t| t <= now)
+        {
+            info!(log, "message expired");
+            self.msg = None;
+            expired = true;
+        }
+
+        if self
+            .image
+            .as_ref()
+            .and_then(|i| i.posted.expires)
+            .is_some_and(|t| t <= now)
+        {
+            info!(log, "image expired");
+            self.image = None;
+            expired = true;
+        }
+
+        if self
+            .timer
+            .as_ref()
+            .and_then(|t| t.posted.expires)
+            .is_some_and(|t| t <= now)
+        {
+            info!(log, "timer expired");
+            self.timer = None;
+            expired = true;
+        }
+
+        if self
+            .power
+            .as_ref()
+            .and_then(|p| p.posted.expires)
+            .is_some_and(|t| t <= now)
+        {
+            info!(log, "power override expired");
+            self.power = None;
+            expired = true;
+        }
+
+        expired
+    }
 }
 
 struct App {
     log: Logger,
-    inner: Mutex<Inner>,
+    config: config::Config,
+    /*
+     * Fonts may be added while the clock is running, so the render loop and
+     * each request take a snapshot of the current font stack:
+     */
+    fonts: Mutex<Arc<FontStack<'static>>>,
+    /*
+     * Rather than sharing a lock on the content with the render loop, every
+     * change publishes a new snapshot through this channel, and the render
+     * loop takes a copy of the latest snapshot at the start of each frame.  A
+     * request that panics part way through can therefore neither leave the
+     * content poisoned nor stop the clock; nor is a request held up while the
+     * render loop sleeps.
+     */
+    content: watch::Sender<Content>,
+    /*
+     * The width and height of the display, which are not known until the
+     * render loop has opened it:
+     */
+    size: watch::Sender<Option<(u32, u32)>>,
+    /*
+     * A copy of the most recent frame sent to the display:
+     */
+    frame: Mutex<RgbImage>,
+    events: broadcast::Sender<http::Event>,
+    metrics: metrics::Metrics,
+    /*
+     * When we last sent a frame to the display, so that we can tell if the
+     * render loop has stopped:
+     */
+    last_paint: Mutex<Instant>,
+}
+
+impl App {
+    fn new(config: config::Config) -> Result<App> {
+        let fonts = Mutex::new(Arc::new(load_fonts(&config)?));
+
+        Ok(App {
+            log: utils::make_log("corner"),
+            config,
+            fonts,
+            content: watch::channel(Default::default()).0,
+            size: watch::channel(None).0,
+            frame: Mutex::new(RgbImage::new(1, 1)),
+            events: broadcast::channel(64).0,
+            metrics: metrics::Metrics::new(),
+            last_paint: Mutex::new(Instant::now()),
+        })
+    }
+
+    fn content(&self) -> Content {
+        self.content.borrow().clone()
+    }
+
+    fn update<F: FnOnce(&mut Content)>(&self, f: F) {
+        self.content.send_modify(f);
+    }
+
+    /*
+     * Discard any expired content, returning true if there was any.
+     */
+    fn expire(&self, now: DateTime<Utc>) -> bool {
+        self.content.send_if_modified(|c| c.expire(&self.log, now))
+    }
+
+    fn size(&self) -> Option<(u32, u32)> {
+        *self.size.borrow()
+    }
+
+    /*
+     * Requests that must fit their content to the display are held until the
+     * render loop has published the size of the display.
+     */
+    async fn wait_for_size(&self) -> (u32, u32) {
+        let mut rx = self.size.subscribe();
+        loop {
+            if let Some(size) = *rx.borrow_and_update() {
+                return size;
+            }
+
+            /*
+             * The sender belongs to us, so it cannot have been dropped.
+             */
+            rx.changed().await.ok();
+        }
+    }
+
+    /*
+     * The theme in effect at this time, according to the local time of day:
+     */
+    fn theme(&self, now: DateTime<Utc>) -> config::Theme {
+        self.config.theme_at(now.home().time())
+    }
+
+    fn theme_name(&self, now: DateTime<Utc>) -> &str {
+        self.config.theme_name_at(now.home().time())
+    }
+
+    /*
+     * Whether the display should be blank at this time, given what has been
+     * posted for display:
+     */
+    fn blanked(&self, now: DateTime<Utc>, c: &Content) -> bool {
+        if let Some(p) = c.power.as_ref() {
+            return !p.on;
+        }
+
+        let Some(power) = self.config.power.as_ref() else {
+            return false;
+        };
+
+        /*
+         * The refresh pattern is usually scheduled for when nobody is in the
+         * office to see it, which is when the display would be blanked, so
+         * light the display for it:
+         */
+        let refresh = self.config.burn_in.refresh.as_ref();
+        if refresh.is_some_and(|r| r.active(now.home().time())) {
+            return false;
+        }
+
+        let posted = c.msg.is_some() || c.image.is_some() || c.timer.is_some();
+        !power.lit(now.home().naive_local())
+            && !(power.wake_on_message && posted)
+    }
+
+    fn fonts(&self) -> Arc<FontStack<'static>> {
+        Arc::clone(&self.fonts.lock_or_recover())
+    }
+
+    /*
+     * Add a font to the front of the font stack, replacing any font of the
+     * same name.  The fonts built into the clock may not be replaced, as the
+     * clock face itself is drawn with them.
+     */
+    fn add_font(&self, fse: FontStackEntry<'static>) -> Result<()> {
+        let mut fonts = self.fonts.lock_or_recover();
+        if fonts
+            .entries
+            .iter()
+            .any(|e| e.source == FontSource::Builtin && e.name == fse.name)
+        {
+            bail!("font {:?} is built in and may not be replaced", fse.name);
+        }
+
+        let name = fse.name.clone();
+        let new = fonts.with_font(fse);
+        for e in fonts.entries.iter() {
+            if e.source == FontSource::Uploaded
+                && e.name != name
+                && !new.entries.iter().any(|n| n.name == e.name)
+            {
+                info!(
+                    self.log,
+                    "discarded uploaded font {:?} to make room", e.name
+                );
+            }
+        }
+
+        *fonts = Arc::new(new);
+        Ok(())
+    }
+
+    fn event(&self, ev: http::Event) {
+        /*
+         * It is not an error for there to be nobody listening.
+         */
+        self.events.send(ev).ok();
+    }
 }
 
 trait RgbExt {
-    fn attenuate(&self, v: f32) -> Self;
+    fn blend(&self, over: Self, alpha: f32, gamma: bool) -> Self;
+}
+
+/*
+ * Blending is best done with linear intensities, rather than the gamma encoded
+ * values in an image.  Converting a channel value from its encoded form is
+ * expensive enough to be worth a lookup table.
+ */
+const GAMMA: f32 = 2.2;
+
+fn to_linear(c: u8) -> f32 {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+
+    TABLE.get_or_init(|| {
+        let mut t = [0f32; 256];
+        for (i, v) in t.iter_mut().enumerate() {
+            *v = (i as f32 / 255.0).powf(GAMMA);
+        }
+        t
+    })[c as usize]
+}
+
+fn from_linear(v: f32) -> u8 {
+    (v.clamp(0.0, 1.0).powf(1.0 / GAMMA) * 255.0).round() as u8
 }
 
 impl RgbExt for Rgb<u8> {
-    fn attenuate(&self, v: f32) -> Rgb<u8> {
+    /*
+     * Composite "over" on top of this colour, with the given opacity.
+     */
+    fn blend(&self, over: Rgb<u8>, alpha: f32, gamma: bool) -> Rgb<u8> {
+        if alpha <= 0.0 {
+            return *self;
+        } else if alpha >= 1.0 {
+            return over;
+        }
+
+        let mix = |under: u8, over: u8| {
+            if gamma {
+                from_linear(
+                    to_linear(over) * alpha + to_linear(under) * (1.0 - alpha),
+                )
+            } else {
+                (over as f32 * alpha + under as f32 * (1.0 - alpha)).round()
+                    as u8
+            }
+        };
+
         Rgb([
-            ((self.0[0] as f32) * v) as u8,
-            ((self.0[1] as f32) * v) as u8,
-            ((self.0[2] as f32) * v) as u8,
+            mix(self.0[0], over.0[0]),
+            mix(self.0[1], over.0[1]),
+            mix(self.0[2], over.0[2]),
         ])
     }
 }
 
+#[derive(Clone, Copy)]
 enum Align {
     Left(u32),
     Right(u32),
     Centre(u32, u32),
+    /*
+     * Start at this position, which may be off the left edge of the display:
+     */
+    At(i32),
+}
+
+impl Align {
+    fn shifted(self, dx: i32) -> Align {
+        match self {
+            Align::Left(x) => Align::Left(x.saturating_add_signed(dx)),
+            Align::Right(x) => Align::Right(x.saturating_add_signed(dx)),
+            Align::Centre(x, w) => {
+                Align::Centre(x.saturating_add_signed(dx), w)
+            }
+            Align::At(x) => Align::At(x.saturating_add(dx)),
+        }
+    }
+}
+
+/*
+ * Scale the brightness of some rows of the image, by a factor between 0 and 1.
+ */
+fn dim(img: &mut RgbImage, rows: Range<u32>, brightness: f32) {
+    if brightness >= 1.0 {
+        return;
+    }
+
+    let b = (brightness.max(0.0) * 256.0) as u32;
+    let stride = img.width() as usize * 3;
+    let raw: &mut [u8] = img;
+    for v in &mut raw[rows.start as usize * stride..rows.end as usize * stride]
+    {
+        *v = ((*v as u32 * b) >> 8) as u8;
+    }
 }
 
 fn horiz_line(
@@ -96,65 +497,454 @@ fn horiz_line(
     }
 }
 
-fn emit_text(
-    text: &str,
-    xa: Align,
+/*
+ * The appearance of a character of text within a message.  The size is a
+ * proportion of the height of the line.
+ */
+#[derive(Clone, Copy)]
+struct Style {
+    rgb: Rgb<u8>,
+    size: f32,
+    bold: bool,
+}
+
+impl Style {
+    fn plain(rgb: Rgb<u8>) -> Style {
+        Style { rgb, size: 1.0, bold: false }
+    }
+}
+
+/*
+ * Text in which each character may have a different style:
+ */
+type StyledText = Vec<(char, Style)>;
+
+fn styled(text: &str, style: Style) -> StyledText {
+    text.chars().map(|c| (c, style)).collect()
+}
+
+/*
+ * We have no bold face, so bold text is drawn by striking each glyph several
+ * times, each a little to the right of the last.  This is how many extra
+ * pixels to the right we should go:
+ */
+fn embolden(pxht: u32, style: &Style) -> u32 {
+    if style.bold {
+        ((pxht as f32 * style.size / 40.0) as u32).max(1)
+    } else {
+        0
+    }
+}
+
+/*
+ * A glyph, ready to be drawn: the font in which it is found (as an index into
+ * the font stack), the character, the scale at which to draw it, and the
+ * position of its origin on the baseline.
+ */
+struct PlacedGlyph {
+    font: usize,
+    c: char,
+    scale: f32,
+    x: f32,
+    y: f32,
+}
+
+/*
+ * Lay out a line of text, with the top of the line at "y", starting from the
+ * left edge.  Every glyph sits on the same baseline, regardless of its size.
+ * Returns the placed glyphs and the width of the whole line.
+ */
+fn layout_text(
+    text: &[(char, Style)],
     y: u32,
     fonts: &FontStack,
     pxht: u32,
-    rgb: Rgb<u8>,
-    img: &mut RgbImage,
     fixed_numbers: bool,
-) -> u32 {
+) -> (Vec<(PlacedGlyph, Style)>, f32) {
     let height = pxht as f32;
 
-    let scale = Scale::uniform(height);
+    let line_scale = Scale::uniform(height);
 
     let num_width = if fixed_numbers {
-        let mut max = 0f32;
-        for c in ('0'..='9').chain(once(' ')).chain(once(':')) {
-            let font = fonts.for_glyph(c);
-            let tw = font.glyph(c).scaled(scale).h_metrics().advance_width;
-            if tw > max {
-                max = tw;
+        Some(fonts.cache.num_width(height, || {
+            let mut max = 0f32;
+            for c in ('0'..='9').chain(once(' ')).chain(once(':')) {
+                let font = fonts.for_glyph(c);
+                let tw =
+                    font.glyph(c).scaled(line_scale).h_metrics().advance_width;
+                if tw > max {
+                    max = tw;
+                }
             }
-        }
-        Some(max)
+            max
+        }))
     } else {
         None
     };
 
-    /*
-     * First, determine the width of the whole string:
-     */
     let mut pgs = Vec::new();
     let mut x = 0f32;
-    for c in text.chars() {
-        let font = fonts.for_glyph(c);
-        let v_metrics = font.v_metrics(scale);
-
-        let g = font.glyph(c).scaled(scale);
-        let (xo, w) =
-            if fixed_numbers && (c.is_ascii_digit() || c == ' ' || c == ':') {
-                let fw = num_width.unwrap();
-                ((fw - g.h_metrics().advance_width) / 2.0, fw)
-            } else {
-                let fw = g.h_metrics().advance_width;
-                (0.0, fw)
-            };
+    /*
+     * The previous glyph, for kerning, and the horizontal extent of the last
+     * glyph that was not a combining mark, over which to put any marks:
+     */
+    let mut prev: Option<(usize, GlyphId, f32)> = None;
+    let mut base: Option<(f32, f32)> = None;
+    for &(c, style) in text {
+        if is_invisible(c) {
+            continue;
+        }
 
-        let g = g.positioned(point(x + xo, y as f32 + v_metrics.ascent));
-        x += w;
+        let idx = fonts.index_for_glyph(c);
+        let font = &fonts.entries[idx].font;
+        let v_metrics = font.v_metrics(line_scale);
+        let scale = height * style.size;
+        let baseline = y as f32 + v_metrics.ascent;
+
+        let sg = font.glyph(c).scaled(Scale::uniform(scale));
+        let extent = |x: f32| {
+            sg.exact_bounding_box()
+                .map(|bb| (x + bb.min.x, x + bb.max.x))
+                .unwrap_or((x, x))
+        };
+
+        if is_combining(c) {
+            if let Some((left, right)) = base {
+                /*
+                 * Centre the mark over the character it follows, without
+                 * advancing:
+                 */
+                let (ml, mr) = extent(0.0);
+                let mx = (left + right) / 2.0 - (ml + mr) / 2.0;
+                pgs.push((
+                    PlacedGlyph { font: idx, c, scale, x: mx, y: baseline },
+                    style,
+                ));
+                continue;
+            }
+        }
+
+        let fixed =
+            fixed_numbers && (c.is_ascii_digit() || c == ' ' || c == ':');
 
-        pgs.push(g);
+        /*
+         * Adjust the space between pairs of characters, like "AV", that the
+         * font says should be closer together (or further apart):
+         */
+        if let Some((pidx, pid, pscale)) = prev {
+            if !fixed && pidx == idx && pscale == scale {
+                x += font.pair_kerning(Scale::uniform(scale), pid, sg.id());
+            }
+        }
+
+        let advance = sg.h_metrics().advance_width;
+        let (xo, w) = if fixed {
+            let fw = num_width.unwrap();
+            ((fw - advance) / 2.0, fw)
+        } else {
+            (0.0, advance)
+        };
+
+        base = Some(extent(x + xo));
+        prev = (!fixed).then_some((idx, sg.id(), scale));
+
+        let g = PlacedGlyph { font: idx, c, scale, x: x + xo, y: baseline };
+        x += w + embolden(pxht, &style) as f32;
+
+        pgs.push((g, style));
     }
-    let text_width = x;
+
+    (pgs, x)
+}
+
+/*
+ * Whether this character is a combining mark, drawn over the character before
+ * it rather than after it.  This covers the blocks of combining marks in
+ * common use, rather than every mark in Unicode.
+ */
+fn is_combining(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0300}'..='\u{036f}'
+            | '\u{0483}'..='\u{0489}'
+            | '\u{0591}'..='\u{05bd}'
+            | '\u{05bf}'
+            | '\u{05c1}'..='\u{05c2}'
+            | '\u{05c4}'..='\u{05c5}'
+            | '\u{05c7}'
+            | '\u{0610}'..='\u{061a}'
+            | '\u{064b}'..='\u{065f}'
+            | '\u{1ab0}'..='\u{1aff}'
+            | '\u{1dc0}'..='\u{1dff}'
+            | '\u{20d0}'..='\u{20ff}'
+            | '\u{fe20}'..='\u{fe2f}'
+    )
+}
+
+/*
+ * Whether this character only affects how those around it are drawn, and has
+ * nothing to draw itself: the variation selectors that ask for emoji or text
+ * presentation (e.g., "\u{2600}\u{fe0f}"), and the joiner used to combine
+ * emoji.  We have no way to draw joined emoji as one, so their parts are drawn
+ * side by side.
+ */
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200d}' | '\u{fe00}'..='\u{fe0f}' | '\u{e0100}'..='\u{e01ef}'
+    )
+}
+
+/*
+ * Put a line of text, which is stored in logical order, into the order in
+ * which it should be drawn from left to right, so that text in right-to-left
+ * scripts like Hebrew reads correctly.  Combining marks stay with the
+ * character they follow.  (We do no contextual shaping, so scripts like
+ * Arabic which need it will not look right.)
+ */
+fn visual_order(line: &[(char, Style)]) -> StyledText {
+    let s = line.iter().map(|(c, _)| *c).collect::<String>();
+    let bidi = BidiInfo::new(&s, None);
+    if !bidi.has_rtl() {
+        return line.to_vec();
+    }
+
+    /*
+     * The bidirectional algorithm deals in byte offsets into the string,
+     * which we must turn back into character indexes:
+     */
+    let offsets = s.char_indices().map(|(o, _)| o).collect::<Vec<_>>();
+    let index = |o: usize| offsets.binary_search(&o).unwrap_or_else(|i| i);
+
+    let mut out = Vec::with_capacity(line.len());
+    for para in bidi.paragraphs.iter() {
+        let (levels, runs) = bidi.visual_runs(para, para.range.clone());
+
+        for run in runs {
+            let chars = &line[index(run.start)..index(run.end)];
+
+            if !levels[run.start].is_rtl() {
+                out.extend_from_slice(chars);
+                continue;
+            }
+
+            /*
+             * Reverse the order of each character and the marks that follow
+             * it:
+             */
+            let mut clusters: Vec<&[(char, Style)]> = Vec::new();
+            let mut start = 0;
+            for i in 1..=chars.len() {
+                if i == chars.len() || !is_combining(chars[i].0) {
+                    clusters.push(&chars[start..i]);
+                    start = i;
+                }
+            }
+            for cl in clusters.into_iter().rev() {
+                out.extend_from_slice(cl);
+            }
+        }
+    }
+
+    out
+}
+
+/*
+ * Measure the width, in pixels, that "emit_styled()" would use to draw this
+ * text.
+ */
+fn text_width(text: &[(char, Style)], fonts: &FontStack, pxht: u32) -> u32 {
+    layout_text(text, 0, fonts, pxht, false).1.ceil() as u32
+}
+
+/*
+ * Break text into lines no wider than "width" pixels at this height, at word
+ * boundaries, honouring any explicit newlines.  Returns None if some word is
+ * too wide to fit on a line by itself.
+ */
+fn wrap_text(
+    text: &[(char, Style)],
+    fonts: &FontStack,
+    pxht: u32,
+    width: u32,
+) -> Option<Vec<StyledText>> {
+    let mut lines = Vec::new();
+
+    for para in text.split(|(c, _)| *c == '\n') {
+        let mut line: StyledText = Vec::new();
+
+        for word in para.split(|(c, _)| c.is_whitespace()) {
+            if word.is_empty() {
+                continue;
+            }
+
+            let mut candidate = line.clone();
+            if !candidate.is_empty() {
+                candidate.push((' ', word[0].1));
+            }
+            candidate.extend_from_slice(word);
+
+            if text_width(&candidate, fonts, pxht) <= width {
+                line = candidate;
+                continue;
+            }
+
+            if line.is_empty() || text_width(word, fonts, pxht) > width {
+                return None;
+            }
+
+            lines.push(std::mem::replace(&mut line, word.to_vec()));
+        }
+
+        lines.push(line);
+    }
+
+    Some(lines)
+}
+
+/*
+ * Find the largest height, no greater than "max", at which the text can be
+ * wrapped to fit within a region of "width" by "height" pixels.  Returns the
+ * height and the wrapped lines, or None if the text will not fit at any
+ * height.
+ */
+fn fit_text(
+    text: &[(char, Style)],
+    fonts: &FontStack,
+    max: u32,
+    width: u32,
+    height: u32,
+) -> Option<(u32, Vec<StyledText>)> {
+    let fits = |pxht: u32| {
+        wrap_text(text, fonts, pxht, width)
+            .filter(|lines| lines.len() as u64 * pxht as u64 <= height as u64)
+    };
+
+    /*
+     * Text grows (more or less) monotonically with its height, so we can
+     * search for the largest height that fits:
+     */
+    let (mut lo, mut hi) = (1, max);
+    fits(lo)?;
+    while lo < hi {
+        let mid = lo + (hi - lo + 1) / 2;
+        if fits(mid).is_some() {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+
+    fits(lo).map(|lines| (lo, lines))
+}
+
+fn emit_text(
+    text: &str,
+    xa: Align,
+    y: u32,
+    fonts: &FontStack,
+    pxht: u32,
+    rgb: Rgb<u8>,
+    img: &mut RgbImage,
+    fixed_numbers: bool,
+    gamma: bool,
+) -> u32 {
+    let text = styled(text, Style::plain(rgb));
+
+    emit_styled(&text, xa, y, fonts, pxht, img, fixed_numbers, gamma)
+}
+
+/*
+ * Draw text over a busy background, such as an image, with a drop shadow or
+ * an outline in black to keep it legible.
+ */
+fn emit_text_over(
+    legibility: http::Legibility,
+    text: &str,
+    xa: Align,
+    y: u32,
+    fonts: &FontStack,
+    pxht: u32,
+    rgb: Rgb<u8>,
+    img: &mut RgbImage,
+    fixed_numbers: bool,
+    gamma: bool,
+) -> u32 {
+    let d = (pxht / 40).max(1) as i32;
+    let offsets: &[(i32, i32)] = match legibility {
+        http::Legibility::Plain => &[],
+        http::Legibility::Shadow => &[(d, d)],
+        http::Legibility::Outline => &[
+            (-d, -d),
+            (0, -d),
+            (d, -d),
+            (-d, 0),
+            (d, 0),
+            (-d, d),
+            (0, d),
+            (d, d),
+        ],
+    };
+
+    for &(dx, dy) in offsets {
+        emit_text(
+            text,
+            xa.shifted(dx),
+            y.saturating_add_signed(dy),
+            fonts,
+            pxht,
+            Rgb([0, 0, 0]),
+            img,
+            fixed_numbers,
+            gamma,
+        );
+    }
+
+    emit_text(text, xa, y, fonts, pxht, rgb, img, fixed_numbers, gamma)
+}
+
+/*
+ * The largest height, no greater than "pxht", at which this text is no wider
+ * than "width".
+ */
+fn shrink_to_fit(
+    text: &str,
+    fonts: &FontStack,
+    pxht: u32,
+    width: u32,
+    fixed_numbers: bool,
+) -> u32 {
+    let text = styled(text, Style::plain(Rgb([0, 0, 0])));
+    let tw = layout_text(&text, 0, fonts, pxht, fixed_numbers).1;
+
+    if tw <= width as f32 {
+        pxht
+    } else {
+        (pxht as f32 * width as f32 / tw) as u32
+    }
+}
+
+fn emit_styled(
+    text: &[(char, Style)],
+    xa: Align,
+    y: u32,
+    fonts: &FontStack,
+    pxht: u32,
+    img: &mut RgbImage,
+    fixed_numbers: bool,
+    gamma: bool,
+) -> u32 {
+    /*
+     * First, determine the width of the whole string:
+     */
+    let (pgs, text_width) = layout_text(text, y, fonts, pxht, fixed_numbers);
 
     /*
      * Now that we know how wide it will be, we know where to begin drawing:
      */
     let xbase = match xa {
         Align::Left(x) => x as f32,
+        Align::At(x) => x as f32,
         Align::Right(x) => x as f32 - text_width,
         Align::Centre(x, w) => {
             let w = w as f32;
@@ -170,60 +960,365 @@ fn emit_text(
         }
     };
 
-    for g in pgs {
-        if let Some(bb) = g.pixel_bounding_box() {
-            g.draw(|x, y, v| {
-                let x = (xbase as u32 + x) as i32 + bb.min.x;
-                let y = y as i32 + bb.min.y;
+    for (g, style) in pgs {
+        let fse = &fonts.entries[g.font];
+        let cov = fonts.cache.glyph(
+            g.font,
+            &fse.font,
+            fse.colour.as_deref(),
+            g.c,
+            g.scale,
+        );
+
+        let x0 = (xbase + g.x).round() as i32 + cov.x;
+        let y0 = g.y.round() as i32 + cov.y;
+
+        for dx in 0..=embolden(pxht, &style) {
+            for (x, y, v, colour) in cov.pixels() {
+                let x = x0 + (x + dx) as i32;
+                let y = y0 + y as i32;
+
+                if x < 0 || y < 0 {
+                    continue;
+                }
 
                 let x = x as u32;
                 let y = y as u32;
 
-                if x < img.width() && y < img.height() {
-                    img.put_pixel(x, y, rgb.attenuate(v));
+                if x >= img.width() || y >= img.height() {
+                    continue;
                 }
-            });
+
+                /*
+                 * The coverage of each pixel by the glyph determines how
+                 * much of the text colour (or the colour of the glyph
+                 * itself, from a colour font) to blend over whatever is
+                 * already there.  Parts of a colour glyph may themselves be
+                 * drawn in the text colour:
+                 */
+                let rgb = match colour {
+                    None => style.rgb,
+                    Some((rgb, text)) => rgb.blend(style.rgb, text, gamma),
+                };
+                let old = *img.get_pixel(x, y);
+                img.put_pixel(x, y, old.blend(rgb, v, gamma));
+            }
         }
     }
 
     text_width as u32
 }
 
+/*
+ * The band along the bottom of the display, where the date is usually shown,
+ * through which a ticker may scroll.  Returns the top row and the height of
+ * the band.
+ */
+fn ticker_band(height: u32) -> (u32, u32) {
+    let ht = height / 4;
+    (height.saturating_sub(ht + 10), ht)
+}
+
+/*
+ * Draw a scrolling message with the top of the line at "y".  The text enters
+ * from the right edge of the display, moves left at the requested speed, and
+ * starts again once it has left the display entirely.  Returns the rows of
+ * the display which may have been drawn on.
+ */
+fn scroll_text(
+    m: &Message,
+    scroll: &Scroll,
+    now: DateTime<Utc>,
+    y: u32,
+    fonts: &FontStack,
+    img: &mut RgbImage,
+    gamma: bool,
+) -> Range<u32> {
+    let line = &m.lines[0];
+
+    let period = img.width() as i64 + text_width(line, fonts, m.height) as i64;
+    let ms = (now - m.posted.at).num_milliseconds().max(0);
+    let travelled = ms * scroll.speed as i64 / 1000;
+    let x = img.width() as i64 - travelled % period;
+
+    emit_styled(
+        line,
+        Align::At(x as i32),
+        y,
+        fonts,
+        m.height,
+        img,
+        false,
+        gamma,
+    );
+
+    /*
+     * Allow for glyphs that reach a little beyond the nominal height of the
+     * line:
+     */
+    let pad = m.height / 4;
+    y.saturating_sub(pad)..y.saturating_add(m.height + pad).min(img.height())
+}
+
 fn load_font(
-    data: &[u8],
+    name: &str,
+    data: &'static [u8],
     glyph_ranges: Vec<RangeInclusive<u32>>,
-) -> Result<FontStackEntry> {
+) -> Result<FontStackEntry<'static>> {
     let Some(font) = Font::try_from_bytes(data) else {
-        bail!("could not load font");
+        bail!("could not load font {name:?}");
+    };
+    let colour = colour::ColourFont::parse(data).map(Arc::new);
+    Ok(FontStackEntry {
+        name: name.to_string(),
+        source: FontSource::Builtin,
+        bytes: data.len(),
+        font,
+        colour,
+        glyph_ranges,
+    })
+}
+
+/*
+ * Load a font that was not built into the program; e.g., from a file named in
+ * the configuration, or uploaded through the HTTP API.
+ */
+fn load_font_owned(
+    name: &str,
+    source: FontSource,
+    data: Vec<u8>,
+    glyph_ranges: Vec<RangeInclusive<u32>>,
+) -> Result<FontStackEntry<'static>> {
+    let bytes = data.len();
+    let colour = colour::ColourFont::parse(&data).map(Arc::new);
+    let Some(font) = Font::try_from_vec(data) else {
+        bail!("could not load font {name:?}");
     };
-    Ok(FontStackEntry { font, glyph_ranges })
+    Ok(FontStackEntry {
+        name: name.to_string(),
+        source,
+        bytes,
+        font,
+        colour,
+        glyph_ranges,
+    })
 }
 
+/*
+ * Parse a range of code points, written in hexadecimal either as a single
+ * code point or as an inclusive range; e.g., "U+1F600", "0590-05FF".
+ */
+fn parse_range(s: &str) -> Result<RangeInclusive<u32>> {
+    let cp = |s: &str| {
+        let s = s.trim();
+        let s =
+            s.strip_prefix("U+").or_else(|| s.strip_prefix("u+")).unwrap_or(s);
+        u32::from_str_radix(s, 16)
+            .ok()
+            .filter(|&c| c <= char::MAX as u32)
+            .ok_or_else(|| anyhow!("invalid code point {s:?}"))
+    };
+
+    let r = match s.split_once('-') {
+        Some((start, end)) => cp(start)?..=cp(end)?,
+        None => {
+            let c = cp(s)?;
+            c..=c
+        }
+    };
+    if r.is_empty() {
+        bail!("empty range {s:?}");
+    }
+    Ok(r)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FontSource {
+    /*
+     * One of the fonts built into the clock:
+     */
+    Builtin,
+    /*
+     * Loaded from a file named in the configuration:
+     */
+    Config,
+    /*
+     * Uploaded through the API while the clock is running:
+     */
+    Uploaded,
+}
+
+/*
+ * Fonts uploaded through the API are kept in memory until the clock is
+ * restarted, so there is a limit on both the number and the total size of
+ * them.  Once either limit is reached, the oldest are discarded to make room.
+ */
+const MAX_UPLOADED_FONTS: usize = 8;
+const MAX_UPLOADED_FONT_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Clone)]
 struct FontStackEntry<'a> {
+    name: String,
+    source: FontSource,
+    /*
+     * The size of the font file:
+     */
+    bytes: usize,
     font: Font<'a>,
+    /*
+     * The colour glyphs in the font, if it has any:
+     */
+    colour: Option<Arc<colour::ColourFont>>,
     glyph_ranges: Vec<RangeInclusive<u32>>,
 }
 
+impl FontStackEntry<'_> {
+    fn claims(&self, c: char) -> bool {
+        self.glyph_ranges.iter().any(|r| r.contains(&(c as u32)))
+    }
+
+    fn has(&self, c: char) -> bool {
+        self.font.glyph(c).id() != GlyphId(0)
+    }
+}
+
 struct FontStack<'a> {
     entries: Vec<FontStackEntry<'a>>,
+    cache: glyphcache::GlyphCache,
 }
 
 impl FontStack<'_> {
+    /*
+     * Prefer the first font that claims the character in its ranges, and
+     * actually has a glyph for it.  Failing that, use the first font that has
+     * a glyph for it at all, so that a character is only drawn as a missing
+     * glyph box if no font can draw it.
+     */
+    fn index_for_glyph(&self, c: char) -> usize {
+        self.entries
+            .iter()
+            .position(|fse| fse.claims(c) && fse.has(c))
+            .or_else(|| self.entries.iter().position(|fse| fse.has(c)))
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .rposition(|fse| fse.source == FontSource::Builtin)
+            })
+            .unwrap_or(self.entries.len() - 1)
+    }
+
     fn for_glyph(&self, c: char) -> &Font {
-        let fse = self
+        &self.entries[self.index_for_glyph(c)].font
+    }
+}
+
+impl FontStack<'static> {
+    /*
+     * Make a new stack with this uploaded font in place of any font of the
+     * same name.  Uploaded fonts go after the built-in fonts, so that they
+     * cannot take over characters that the built-in fonts draw (e.g., the
+     * digits on the clock face), with the most recently uploaded first; the
+     * oldest are discarded once there are too many.  The glyph cache is keyed
+     * by the position of each font in the stack, so the new stack starts with
+     * an empty one.
+     */
+    fn with_font(&self, fse: FontStackEntry<'static>) -> FontStack<'static> {
+        let mut entries = self
             .entries
             .iter()
-            .filter(|fse| {
-                fse.glyph_ranges.iter().any(|r| r.contains(&(c as u32)))
-            })
-            .next();
+            .filter(|e| e.name != fse.name)
+            .cloned()
+            .collect::<Vec<_>>();
+        let first = entries
+            .iter()
+            .position(|e| e.source == FontSource::Uploaded)
+            .unwrap_or(entries.len());
+        entries.insert(first, fse);
+
+        loop {
+            let uploaded = &entries[first..];
+            let bytes = uploaded.iter().map(|e| e.bytes).sum::<usize>();
+            if uploaded.len() == 1
+                || (uploaded.len() <= MAX_UPLOADED_FONTS
+                    && bytes <= MAX_UPLOADED_FONT_BYTES)
+            {
+                break;
+            }
+            entries.pop();
+        }
+
+        FontStack { entries, cache: Default::default() }
+    }
+}
+
+fn load_fonts(config: &config::Config) -> Result<FontStack<'static>> {
+    /*
+     * Fonts from the configuration come first, so that they may take over
+     * ranges from the built-in fonts:
+     */
+    let mut entries = config
+        .fonts
+        .iter()
+        .map(|f| {
+            let ranges = f
+                .ranges
+                .iter()
+                .map(|r| parse_range(r))
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("font {:?}", f.path))?;
+            let data = std::fs::read(&f.path)
+                .with_context(|| format!("reading font {:?}", f.path))?;
+            load_font_owned(
+                &f.path.to_string_lossy(),
+                FontSource::Config,
+                data,
+                ranges,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    entries.extend([
+        load_font(
+            "unifont",
+            include_bytes!("../fonts/unifont-15.0.01.ttf"),
+            vec![
+                /*
+                 * Basic icons in this range (sun and moon):
+                 */
+                0x2600..=0x26ff,
+                /*
+                 * Dingbats (e.g., aeroplane):
+                 */
+                0x2700..=0x27bf,
+            ],
+        )?,
+        load_font(
+            "unifont_upper",
+            include_bytes!("../fonts/unifont_upper-15.0.01.ttf"),
+            vec![
+                /*
+                 * Birthday Cake, Jack-o-lantern, Christmas Tree:
+                 */
+                0x1F382..=0x1F384,
+                /*
+                 * Bottle with popping cork:
+                 */
+                0x1F37E..=0x1F37E,
+            ],
+        )?,
+        load_font(
+            "Domine",
+            include_bytes!("../fonts/Domine-Regular.ttf"),
+            vec![
+                /*
+                 * Everything else:
+                 */
+                1..=0x25FF,
+            ],
+        )?,
+    ]);
 
-        if let Some(fse) = fse {
-            &fse.font
-        } else {
-            &self.entries[self.entries.len() - 1].font
-        }
-    }
+    Ok(FontStack { entries, cache: Default::default() })
 }
 
 trait DateTimeExt {
@@ -238,23 +1333,121 @@ impl DateTimeExt for DateTime<Utc> {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let app = Arc::new(App {
-        log: utils::make_log("corner"),
-        inner: Mutex::new(Inner {
-            msg: None,
-            image: None,
-            height: 1,
-            width: 1,
-        }),
-    });
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let config = match args.as_slice() {
+        [cmd] if cmd == "openapi" => {
+            /*
+             * Emit the OpenAPI document for the HTTP API, rather than running
+             * the clock:
+             */
+            println!("{}", serde_json::to_string_pretty(&http::spec()?)?);
+            return Ok(());
+        }
+        [cmd] if cmd == "bench" => {
+            /*
+             * Measure the cost of drawing text, rather than running the
+             * clock:
+             */
+            let config: config::Config = Default::default();
+            bench::bench(&load_fonts(&config)?, config.display.gamma_correct);
+            return Ok(());
+        }
+        [path] => config::Config::load(path)?,
+        [] => Default::default(),
+        _ => bail!("usage: clock [CONFIG_FILE | openapi | bench]"),
+    };
+
+    let app = Arc::new(App::new(config)?);
+
+    if app.config.http.enabled {
+        let app0 = app.clone();
+        tokio::task::spawn(async {
+            http::server(app0).await.unwrap();
+        });
+    }
+
+    if let Some(control) = app.config.control.as_ref() {
+        let listener = control::listen(&control.socket)?;
+        let app0 = app.clone();
+        tokio::task::spawn(control::server(app0, listener));
+    }
 
+    /*
+     * Keep an eye on the render loop, so that we notice if it stops producing
+     * frames.
+     */
     let app0 = app.clone();
-    tokio::task::spawn(async {
-        http::server(app0, SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 8888))
-            .await
-            .unwrap();
+    tokio::task::spawn(async move {
+        watchdog(app0).await;
     });
 
+    loop {
+        let app0 = app.clone();
+        let res = tokio::task::spawn_blocking(move || render_loop(&app0)).await;
+
+        let e = match res {
+            Ok(Ok(())) => anyhow!("render loop exited unexpectedly"),
+            Ok(Err(e)) => e,
+            Err(e) => anyhow!("render loop panicked: {e}"),
+        };
+
+        if !app.config.watchdog.restart {
+            return Err(e);
+        }
+
+        error!(app.log, "restarting render loop: {e:?}");
+        app.event(http::Event::Error(format!("render loop: {e}")));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/*
+ * Watch for frames that are not being painted on time.  If the render loop
+ * appears to have stopped, there is not much we can do about it from here; if
+ * restarts are enabled, exit so that the service manager can restart us.
+ */
+async fn watchdog(app: Arc<App>) {
+    let stale = Duration::from_secs(app.config.watchdog.stale_secs);
+    let mut stalled = false;
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let age = app.last_paint.lock_or_recover().elapsed();
+
+        if age <= stale {
+            if stalled {
+                info!(app.log, "render loop has recovered");
+                stalled = false;
+            }
+            continue;
+        }
+
+        if !stalled {
+            error!(app.log, "no frame painted for {} seconds", age.as_secs());
+            app.event(http::Event::Error(format!(
+                "no frame painted for {} seconds",
+                age.as_secs()
+            )));
+            stalled = true;
+        }
+
+        if app.config.watchdog.restart {
+            error!(app.log, "render loop stalled; exiting");
+            std::process::exit(1);
+        }
+    }
+}
+
+/*
+ * The number of stripes into which the framebuffer divides the display, each a
+ * contiguous run of pixels which is written only if it has changed.  Other
+ * backends redraw the whole display, but transitions are made to match these
+ * stripes wherever they are shown.
+ */
+const STRIPES: usize = 256;
+
+fn render_loop(app: &App) -> Result<()> {
     #[cfg(target_os = "linux")]
     /*
      * The target display in the office is 5120 x 1440, but obviously that's
@@ -266,46 +1459,6 @@ async fn main() -> Result<()> {
     #[cfg(target_os = "illumos")]
     let mut fb = fb::Framebuffer::new()?;
 
-    let fonts = FontStack {
-        entries: vec![
-            load_font(
-                include_bytes!("../fonts/unifont-15.0.01.ttf"),
-                vec![
-                    /*
-                     * Basic icons in this range (sun and moon):
-                     */
-                    0x2600..=0x26ff,
-                    /*
-                     * Dingbats (e.g., aeroplane):
-                     */
-                    0x2700..=0x27bf,
-                ],
-            )?,
-            load_font(
-                include_bytes!("../fonts/unifont_upper-15.0.01.ttf"),
-                vec![
-                    /*
-                     * Birthday Cake, Jack-o-lantern, Christmas Tree:
-                     */
-                    0x1F382..=0x1F384,
-                    /*
-                     * Bottle with popping cork:
-                     */
-                    0x1F37E..=0x1F37E,
-                ],
-            )?,
-            load_font(
-                include_bytes!("../fonts/Domine-Regular.ttf"),
-                vec![
-                    /*
-                     * Everything else:
-                     */
-                    1..=0x25FF,
-                ],
-            )?,
-        ],
-    };
-
     #[cfg(target_os = "linux")]
     let mut img = RgbImage::new(fb.width(), fb.height());
 
@@ -315,175 +1468,670 @@ async fn main() -> Result<()> {
         fb.height().try_into().unwrap(),
     );
 
-    {
-        let mut i = app.inner.lock().unwrap();
-        i.height = img.height();
-        i.width = img.width();
-    }
+    app.size.send_replace(Some((img.width(), img.height())));
 
     let clocks = [("Oxide", chrono_tz::US::Pacific)];
 
     let ch = img.height() / clocks.len() as u32;
 
     #[cfg(target_os = "illumos")]
-    fn paint(fb: &mut fb::Framebuffer, img: &ImageBuffer<Rgb<u8>, Vec<u8>>) {
-        fb.apply(img);
+    type Screen = fb::Framebuffer;
+
+    #[cfg(target_os = "linux")]
+    type Screen = x11::App<'static>;
+
+    /*
+     * Send a rendered frame to the display.  The time at which rendering began
+     * is used to measure how long it took.  If the caller knows that only some
+     * rows of the frame have changed since the last frame, they may pass those
+     * rows as "damage" so that we need not look at the rest.  If the frame
+     * shows something different from the last, any transition between the two
+     * is shown first.
+     */
+    fn paint(
+        fb: &mut Screen,
+        app: &App,
+        burn_in: &mut burnin::BurnIn,
+        transitions: &mut transition::Transition,
+        img: &mut RgbImage,
+        start: Instant,
+        damage: Option<Range<u32>>,
+    ) {
+        let frame_time =
+            Duration::from_secs(1) / app.config.display.frame_rate.max(1);
+
+        let playing = Instant::now();
+        let played = transitions.play(img, frame_time, |tween, rows| {
+            present(fb, app, burn_in, tween, Instant::now(), Some(rows));
+        });
+        let damage = damage.filter(|_| !played);
+
+        /*
+         * The time spent playing a transition is not part of the time taken
+         * to render this frame:
+         */
+        let start = start + playing.elapsed();
+
+        transitions.remember(img, damage.clone());
+        present(fb, app, burn_in, img, start, damage);
+    }
+
+    /*
+     * Send a single frame to the display, first moving it to avoid burn-in,
+     * and dimming it if the current theme calls for it.
+     */
+    fn present(
+        fb: &mut Screen,
+        app: &App,
+        burn_in: &mut burnin::BurnIn,
+        img: &mut RgbImage,
+        start: Instant,
+        damage: Option<Range<u32>>,
+    ) {
+        let now = Utc::now();
+
+        /*
+         * The X11 window is always redrawn in full, so every row must be
+         * dimmed, not just those which have changed:
+         */
+        let damage = if cfg!(target_os = "linux") { None } else { damage };
+
+        let damage = burn_in.apply(img, now, damage);
+        let rows = damage.clone().unwrap_or(0..img.height());
+        dim(img, rows, app.theme(now).brightness);
+
+        let painting = Instant::now();
+        app.metrics.render_time(painting - start);
+
+        send(fb, app, img, damage.clone());
+
+        app.metrics.paint_time(painting.elapsed());
+        save_frame(app, img, damage);
+        *app.last_paint.lock_or_recover() = Instant::now();
+    }
+
+    #[cfg(target_os = "illumos")]
+    fn send(
+        fb: &mut fb::Framebuffer,
+        app: &App,
+        img: &RgbImage,
+        damage: Option<Range<u32>>,
+    ) {
+        let res = match damage {
+            Some(rows) => {
+                fb.apply_rows(img, rows.start as usize..rows.end as usize)
+            }
+            None => fb.apply(img),
+        };
+        match res {
+            Ok(dirty) => app.metrics.dirty_buckets(dirty),
+            Err(e) => {
+                error!(app.log, "framebuffer error: {e:?}");
+                app.event(http::Event::Error(format!("framebuffer: {e}")));
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send(
+        fb: &mut x11::App,
+        app: &App,
+        img: &RgbImage,
+        _damage: Option<Range<u32>>,
+    ) {
+        if let Err(e) = fb.apply(img).and_then(|_| fb.poll()) {
+            error!(app.log, "x11 error: {e:?}");
+            app.event(http::Event::Error(format!("x11: {e}")));
+        }
+    }
+
+    /*
+     * Keep a copy of the frame for screenshots.  Copying the whole frame many
+     * times a second is expensive, so copy only the damaged rows if we can.
+     */
+    fn save_frame(
+        app: &App,
+        img: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        damage: Option<Range<u32>>,
+    ) {
+        let mut frame = app.frame.lock_or_recover();
+
+        match damage {
+            Some(rows) if frame.dimensions() == img.dimensions() => {
+                let stride = img.width() as usize * 3;
+                let r =
+                    rows.start as usize * stride..rows.end as usize * stride;
+                let dst: &mut [u8] = &mut frame;
+                dst[r.clone()].copy_from_slice(&img.as_raw()[r]);
+            }
+            _ => frame.clone_from(img),
+        }
     }
 
+    /*
+     * Turn the display itself off while it is blank, and on again, where we
+     * can.  The framebuffer offers no such control, so it is just left black.
+     */
+    #[cfg(target_os = "illumos")]
+    fn set_power(_fb: &mut fb::Framebuffer, _app: &App, _on: bool) {}
+
     #[cfg(target_os = "linux")]
-    fn paint(fb: &mut x11::App, img: &ImageBuffer<Rgb<u8>, Vec<u8>>) {
-        fb.apply(img);
-        fb.poll().expect("x11 poll");
+    fn set_power(fb: &mut x11::App, app: &App, on: bool) {
+        if let Err(e) = fb.set_dpms(on) {
+            error!(app.log, "x11 error: {e:?}");
+            app.event(http::Event::Error(format!("x11: {e}")));
+        }
     }
 
+    let frame_time =
+        Duration::from_secs(1) / app.config.display.frame_rate.max(1);
+
+    let mut burn_in = burnin::BurnIn::new(&app.config.burn_in);
+    let mut transitions =
+        transition::Transition::new(&app.config.display.transition);
+
+    /*
+     * The scrolling message drawn in the previous frame, if any.  If we are
+     * drawing the same message again, only the rows it occupies can have
+     * changed.
+     */
+    let mut last_scroll: Option<Arc<Message>> = None;
+
+    /*
+     * The last second displayed by the clock, used to detect any seconds that
+     * we skipped over:
+     */
+    let mut last_second: Option<i64> = None;
+
+    /*
+     * An earlier render loop (e.g., one restarted by the watchdog) may have
+     * left the display off, so make sure it starts out in the right state:
+     */
+    let mut blanked = app.blanked(Utc::now(), &app.content());
+    set_power(&mut fb, app, !blanked);
+
     loop {
         let now = Utc::now();
         let inow = Instant::now();
 
         img.fill(0);
 
-        {
-            let i = app.inner.lock().unwrap();
+        if app.expire(now) {
+            http::notify(app);
+        }
+
+        let c = app.content();
+
+        /*
+         * Outside the hours in which the display is to be lit, or if it has
+         * been turned off, keep it black:
+         */
+        let blank = app.blanked(now, &c);
+        if blank != blanked {
+            info!(app.log, "display {}", if blank { "blanked" } else { "lit" });
+            set_power(&mut fb, &app, !blank);
+            blanked = blank;
+            http::notify(&app);
+        }
+
+        if blanked {
+            transitions.show(Scene::Blank, None);
+            paint(
+                &mut fb,
+                &app,
+                &mut burn_in,
+                &mut transitions,
+                &mut img,
+                inow,
+                None,
+            );
+
+            last_second = None;
+            last_scroll = None;
+            std::thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+
+        if burn_in.refreshing(now) {
+            burn_in.refresh_pattern(&mut img, now);
+            transitions.show(Scene::Refresh, None);
+            paint(
+                &mut fb,
+                &app,
+                &mut burn_in,
+                &mut transitions,
+                &mut img,
+                inow,
+                None,
+            );
+
+            last_second = None;
+            last_scroll = None;
+            std::thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+
+        let theme = app.theme(now);
+        let fonts = app.fonts();
+        let fonts = fonts.as_ref();
+        let gamma = app.config.display.gamma_correct;
+
+        let prev_scroll = last_scroll.take();
+        let same_scroll = |m: &Arc<Message>| {
+            prev_scroll.as_ref().is_some_and(|p| Arc::ptr_eq(p, m))
+        };
+
+        /*
+         * A scrolling message in the bottom band is drawn along with the time,
+         * rather than in place of it:
+         */
+        let ticker = c
+            .msg
+            .as_ref()
+            .filter(|m| m.scroll.is_some_and(|s| s.band == http::Band::Bottom));
+
+        /*
+         * Note what this frame will show, in the same order of precedence as
+         * we draw things below, so that we can move from the last frame to
+         * this one with a transition if it shows something else.  A ticker is
+         * moving all the time, so we must not hold it up for the digits of the
+         * clock to change.
+         */
+        let scene = if let Some(im) = c.image.as_ref() {
+            Scene::Image(im.posted.id)
+        } else if let Some(m) = c.msg.as_ref().filter(|_| ticker.is_none()) {
+            Scene::Message(m.posted.id)
+        } else if let Some(t) = c.timer.as_ref() {
+            Scene::Timer(t.posted.id)
+        } else {
+            Scene::Clock
+        };
+        transitions.show(scene, ticker.is_none().then_some(now.timestamp()));
+
+        /*
+         * We've been given a picture to display via the HTTP API.  Draw
+         * that on the screen:
+         */
+        if let Some(im) = c.image.as_ref() {
+            let over = &im.img;
 
             /*
-             * We've been given a picture to display via the HTTP API.  Draw
-             * that on the screen:
+             * Screen ratio:
              */
-            if let Some(over) = i.image.as_ref() {
+            let irat = img.width() as f32 / img.height() as f32;
+
+            /*
+             * Image ratio:
+             */
+            let orat = over.width() as f32 / over.height() as f32;
+
+            let (w, h) = if irat > orat {
                 /*
-                 * Screen ratio:
+                 * The display is wider than the picture.
                  */
-                let irat = img.width() as f32 / img.height() as f32;
-
+                ((img.height() as f32 * orat) as u32, img.height())
+            } else {
                 /*
-                 * Image ratio:
+                 * The picture is wider than the display.
                  */
-                let orat = over.width() as f32 / over.height() as f32;
-
-                let (w, h) = if irat > orat {
-                    /*
-                     * The display is wider than the picture.
-                     */
-                    ((img.height() as f32 * orat) as u32, img.height())
-                } else {
-                    /*
-                     * The picture is wider than the display.
-                     */
-                    (img.width(), (img.width() as f32 / orat) as u32)
-                };
+                (img.width(), (img.width() as f32 / orat) as u32)
+            };
 
-                let x = (img.width() - w) / 2;
-                let y = (img.height() - h) / 2;
+            let x = match im.align {
+                http::ImageAlign::Left => 0,
+                http::ImageAlign::Centre => img.width().saturating_sub(w) / 2,
+                http::ImageAlign::Right => img.width().saturating_sub(w),
+            };
+            let y = img.height().saturating_sub(h) / 2;
 
-                img.copy_from(over, x, y).ok();
+            img.copy_from(over, x, y).ok();
 
-                paint(&mut fb, &img);
+            /*
+             * Unless the clock is to be drawn on top of the image, that's all
+             * there is to it:
+             */
+            if im.clock.is_none() {
+                paint(
+                    &mut fb,
+                    &app,
+                    &mut burn_in,
+                    &mut transitions,
+                    &mut img,
+                    inow,
+                    None,
+                );
 
+                last_second = None;
                 std::thread::sleep(Duration::from_secs(1));
                 continue;
             }
+        }
+
+        /*
+         * If the clock is being drawn over an image, that takes precedence
+         * over any message or timer, and the clock may be confined to one
+         * half of the display:
+         */
+        let (cx, cw, legibility) = match c.image.as_ref() {
+            Some(im) => {
+                let half = img.width() / 2;
+                let (cx, cw) = match im.clock {
+                    Some(http::ClockPosition::Left) => (0, half),
+                    Some(http::ClockPosition::Right) => {
+                        (half, img.width() - half)
+                    }
+                    _ => (0, img.width()),
+                };
+                (cx, cw, im.legibility)
+            }
+            None => (0, img.width(), http::Legibility::Plain),
+        };
 
+        /*
+         * We've been given a message (text) to display on the screen via
+         * the HTTP API.  Draw that on the screen:
+         */
+        if let Some((m, scroll)) = c
+            .msg
+            .as_ref()
+            .filter(|_| ticker.is_none() && c.image.is_none())
+            .and_then(|m| m.scroll.map(|s| (m, s)))
+        {
             /*
-             * We've been given a message (text) to display on the screen via
-             * the HTTP API.  Draw that on the screen:
+             * A scrolling message across the whole display:
              */
-            if let Some(m) = i.msg.as_ref() {
-                emit_text(
-                    &m.text,
+            let y = img.height().saturating_sub(m.height) / 2;
+            let rows = scroll_text(m, &scroll, now, y, fonts, &mut img, gamma);
+
+            let damage = same_scroll(m).then_some(rows);
+            paint(
+                &mut fb,
+                &app,
+                &mut burn_in,
+                &mut transitions,
+                &mut img,
+                inow,
+                damage,
+            );
+
+            last_second = None;
+            last_scroll = Some(m.clone());
+            std::thread::sleep(frame_time.saturating_sub(inow.elapsed()));
+            continue;
+        }
+
+        if let Some(m) =
+            c.msg.as_ref().filter(|_| ticker.is_none() && c.image.is_none())
+        {
+            let total = m.height.saturating_mul(m.lines.len() as u32);
+            let mut y = img.height().saturating_sub(total) / 2;
+
+            for line in m.lines.iter() {
+                emit_styled(
+                    line,
                     Align::Centre(0, img.width()),
-                    (img.height() - m.height) / 2,
-                    &fonts,
+                    y,
+                    fonts,
                     m.height,
-                    m.rgb,
                     &mut img,
                     false,
+                    gamma,
                 );
+                y = y.saturating_add(m.height);
+            }
 
-                paint(&mut fb, &img);
-
-                if let Some(flash) = m.flash {
-                    std::thread::sleep(flash);
+            paint(
+                &mut fb,
+                &app,
+                &mut burn_in,
+                &mut transitions,
+                &mut img,
+                inow,
+                None,
+            );
+            last_second = None;
 
-                    img.fill(0);
-                    paint(&mut fb, &img);
+            if let Some(flash) = m.flash {
+                std::thread::sleep(flash);
 
-                    std::thread::sleep(flash);
-                } else {
-                    /*
-                     * When not actually rendering the time, and not flashing,
-                     * just sleep for a second.
-                     */
-                    std::thread::sleep(Duration::from_secs(1));
-                }
+                img.fill(0);
+                paint(
+                    &mut fb,
+                    &app,
+                    &mut burn_in,
+                    &mut transitions,
+                    &mut img,
+                    Instant::now(),
+                    None,
+                );
 
-                continue;
+                std::thread::sleep(flash);
+            } else {
+                /*
+                 * When not actually rendering the time, and not flashing,
+                 * just sleep for a second.
+                 */
+                std::thread::sleep(Duration::from_secs(1));
             }
+
+            continue;
         }
 
-        /*
-         * If neither an image nor a message have been furnished for display,
-         * render the current time and date.
-         */
-        for (idx, (_name, tz)) in clocks.iter().enumerate() {
-            let now = now.with_timezone(tz);
-            let yc = ch * idx as u32;
-
-            if idx > 0 {
-                horiz_line(
-                    0,
-                    img.width(),
-                    yc,
-                    4,
-                    Rgb([0xc8, 0xc8, 0xc8]),
-                    &mut img,
+        if let Some(t) = c.timer.as_ref().filter(|_| c.image.is_none()) {
+            /*
+             * A countdown timer has been started via the HTTP API.  Display
+             * the time remaining, rounded up to the next whole second so that
+             * we reach zero at the moment the timer ends:
+             */
+            let ms = (t.ends - now).num_milliseconds().max(0);
+            let secs = (ms + 999) / 1000;
+
+            let text = if secs >= 3600 {
+                format!(
+                    "{}:{:02}:{:02}",
+                    secs / 3600,
+                    (secs / 60) % 60,
+                    secs % 60
                 )
-            }
-
-            let ht = ch / 4;
-
-            let grey = Rgb([0x7d, 0x83, 0x85]);
+            } else {
+                format!("{:02}:{:02}", secs / 60, secs % 60)
+            };
 
-            emit_text(
-                &now.format("%d %B %Y").to_string(),
-                Align::Right(img.width() - 1),
-                yc + ch - ht - 10,
-                &fonts,
-                ht,
-                grey,
-                &mut img,
-                false,
-            );
+            /*
+             * Once the timer has run out, flash the display in red to draw
+             * attention to it:
+             */
+            let rgb = if secs > 0 {
+                t.rgb.unwrap_or(Rgb(theme.time))
+            } else if now.timestamp() % 2 == 0 {
+                Rgb([0xff, 0x00, 0x00])
+            } else {
+                Rgb([0x00, 0x00, 0x00])
+            };
 
+            let ht = img.height() * 10 / 18;
             emit_text(
-                &now.format("%A").to_string(),
-                Align::Left(0),
-                yc + ch - ht - 10,
-                &fonts,
+                &text,
+                Align::Centre(0, img.width()),
+                (img.height() - ht - (ht / 3)) / 2,
+                fonts,
                 ht,
-                grey,
+                rgb,
                 &mut img,
-                false,
+                true,
+                gamma,
             );
 
+            if let Some(label) = t.label.as_deref().filter(|_| ticker.is_none())
+            {
+                let ht = img.height() / 4;
+                emit_text(
+                    label,
+                    Align::Centre(0, img.width()),
+                    img.height() - ht - 10,
+                    fonts,
+                    ht,
+                    Rgb(theme.date),
+                    &mut img,
+                    false,
+                    gamma,
+                );
+            }
+        } else {
             /*
-             * Approximately oxide green:
+             * If no image, message, or timer has been furnished for display,
+             * render the current time and date.
              */
-            let colour = Rgb([0x48, 0xd5, 0x97]);
+            for (idx, (_name, tz)) in clocks.iter().enumerate() {
+                let now = now.with_timezone(tz);
+                let yc = ch * idx as u32;
+
+                if idx > 0 {
+                    horiz_line(
+                        cx,
+                        cx + cw,
+                        yc,
+                        4,
+                        Rgb(theme.separator),
+                        &mut img,
+                    )
+                }
 
-            let ht = ch * 10 / 18;
-            emit_text(
-                &now.format("%H:%M:%S").to_string(),
-                Align::Centre(0, img.width()),
-                yc + (ch - ht - (ht / 3)) / 2,
-                &fonts,
-                ht,
-                colour,
+                let ht = ch / 4;
+
+                let grey = Rgb(theme.date);
+
+                /*
+                 * A ticker takes the place of the date beneath the last
+                 * clock:
+                 */
+                if ticker.is_none() || idx + 1 < clocks.len() {
+                    let y = yc + ch - ht - 10;
+                    let date = now.format("%d %B %Y").to_string();
+                    let day = now.format("%A").to_string();
+                    let both = format!("{day} {date}");
+                    let width = |text: &str| {
+                        text_width(&styled(text, Style::plain(grey)), fonts, ht)
+                    };
+
+                    if width(&day) + width(&date) + ht / 2 <= cw {
+                        emit_text_over(
+                            legibility,
+                            &date,
+                            Align::Right(cx + cw - 1),
+                            y,
+                            fonts,
+                            ht,
+                            grey,
+                            &mut img,
+                            false,
+                            gamma,
+                        );
+
+                        emit_text_over(
+                            legibility,
+                            &day,
+                            Align::Left(cx),
+                            y,
+                            fonts,
+                            ht,
+                            grey,
+                            &mut img,
+                            false,
+                            gamma,
+                        );
+                    } else {
+                        /*
+                         * In a narrower space, such as beside an image, the
+                         * day and date will not fit at either side; put them
+                         * together in the middle instead:
+                         */
+                        let sht = shrink_to_fit(&both, fonts, ht, cw, false);
+                        emit_text_over(
+                            legibility,
+                            &both,
+                            Align::Centre(cx, cw),
+                            y + (ht - sht),
+                            fonts,
+                            sht,
+                            grey,
+                            &mut img,
+                            false,
+                            gamma,
+                        );
+                    }
+                }
+
+                let colour = Rgb(theme.time);
+
+                /*
+                 * Shrink the time, if need be, to fit the space available,
+                 * keeping it centred where it would otherwise be:
+                 */
+                let full = ch * 10 / 18;
+                let text = now.format("%H:%M:%S").to_string();
+                let ht = shrink_to_fit(&text, fonts, full, cw, true);
+                emit_text_over(
+                    legibility,
+                    &text,
+                    Align::Centre(cx, cw),
+                    yc + (ch - full - (full / 3)) / 2 + (full - ht) / 2,
+                    fonts,
+                    ht,
+                    colour,
+                    &mut img,
+                    true,
+                    gamma,
+                );
+            }
+        }
+
+        let second = now.timestamp();
+
+        if let Some(m) = ticker {
+            let scroll = m.scroll.unwrap();
+            let (y, _) = ticker_band(img.height());
+            let rows = scroll_text(m, &scroll, now, y, fonts, &mut img, gamma);
+
+            /*
+             * Unless the time has changed since the last frame, only the
+             * ticker has moved:
+             */
+            let damage =
+                (same_scroll(m) && last_second == Some(second)).then_some(rows);
+            paint(
+                &mut fb,
+                &app,
+                &mut burn_in,
+                &mut transitions,
                 &mut img,
-                true,
+                inow,
+                damage,
+            );
+        } else {
+            paint(
+                &mut fb,
+                &app,
+                &mut burn_in,
+                &mut transitions,
+                &mut img,
+                inow,
+                None,
             );
         }
 
-        paint(&mut fb, &img);
+        if let Some(last) = last_second {
+            if second > last + 1 {
+                app.metrics.missed_seconds((second - last - 1) as u64);
+            }
+        }
+        last_second = Some(second);
+
+        if let Some(m) = ticker {
+            last_scroll = Some(m.clone());
+            std::thread::sleep(frame_time.saturating_sub(inow.elapsed()));
+            continue;
+        }
 
         std::thread::sleep(
             /*
@@ -508,3 +2156,85 @@ async fn main() -> Result<()> {
         );
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /*
+     * A request that panics part way through changing the content must not
+     * stop the render loop from reading the content, nor stop later requests
+     * from changing it.
+     */
+    #[test]
+    fn panic_in_update() {
+        let app = Arc::new(App::new(Default::default()).unwrap());
+
+        let app0 = Arc::clone(&app);
+        let res = std::thread::spawn(move || {
+            app0.update(|_| panic!("request failed part way through"));
+        })
+        .join();
+        assert!(res.is_err());
+
+        let c = app.content();
+        assert!(c.msg.is_none() && c.image.is_none() && c.timer.is_none());
+        assert!(!app.expire(Utc::now()));
+        let state = serde_json::to_value(http::current_state(&app)).unwrap();
+        assert_eq!(state["mode"], "clock");
+
+        let mut rx = app.content.subscribe();
+        let now = Utc::now();
+        app.update(|c| {
+            c.timer = Some(Arc::new(Timer {
+                ends: now + chrono::Duration::seconds(60),
+                label: None,
+                rgb: None,
+                posted: Posted::new(now, "test".into(), None),
+            }))
+        });
+        assert!(rx.has_changed().unwrap());
+        assert!(app.content().timer.is_some());
+        let state = serde_json::to_value(http::current_state(&app)).unwrap();
+        assert_eq!(state["mode"], "timer");
+    }
+
+    /*
+     * Uploaded fonts must not take over characters drawn by the built-in
+     * fonts, and only a limited number of them are kept.
+     */
+    #[test]
+    fn uploaded_fonts() {
+        let app = App::new(Default::default()).unwrap();
+        let upload = |name: &str| {
+            let fse = load_font_owned(
+                name,
+                FontSource::Uploaded,
+                include_bytes!("../fonts/Domine-Regular.ttf").to_vec(),
+                vec![0x30..=0x39],
+            )
+            .unwrap();
+            app.add_font(fse).unwrap();
+        };
+
+        upload("digits");
+        let fonts = app.fonts();
+        let fse = &fonts.entries[fonts.index_for_glyph('0')];
+        assert!(fse.source == FontSource::Builtin);
+
+        for i in 0..=MAX_UPLOADED_FONTS {
+            upload(&format!("font{i}"));
+        }
+        let fonts = app.fonts();
+        let uploaded = fonts
+            .entries
+            .iter()
+            .filter(|e| e.source == FontSource::Uploaded)
+            .map(|e| e.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(uploaded.len(), MAX_UPLOADED_FONTS);
+        assert_eq!(uploaded[0], format!("font{MAX_UPLOADED_FONTS}"));
+        assert!(!uploaded.contains(&"digits"));
+        assert!(!uploaded.contains(&"font0"));
+    }
+}
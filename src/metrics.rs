@@ -0,0 +1,216 @@
+/*
+ * Copyright 2024 Oxide Computer Company
+ */
+
+/*
+ * A small collection of counters and histograms describing the performance of
+ * the clock, rendered in the Prometheus text exposition format by
+ * "GET /metrics".
+ */
+
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+use crate::utils::MutexExt;
+
+/*
+ * The name of the display backend, used to label paint times:
+ */
+#[cfg(target_os = "illumos")]
+const BACKEND: &str = "framebuffer";
+#[cfg(target_os = "linux")]
+const BACKEND: &str = "x11";
+
+const SECONDS: &[f64] =
+    &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/*
+ * The framebuffer is drawn in 256 stripes; see "fb::Framebuffer::apply()".
+ */
+const BUCKETS: &[f64] = &[0.0, 1.0, 4.0, 16.0, 32.0, 64.0, 128.0, 256.0];
+
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, v: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if v <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += v;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            writeln!(
+                out,
+                "{name}_bucket{{{labels}{sep}le=\"{bound}\"}} {count}"
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {}",
+            self.count
+        )
+        .unwrap();
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        writeln!(out, "{name}_sum{labels} {}", self.sum).unwrap();
+        writeln!(out, "{name}_count{labels} {}", self.count).unwrap();
+    }
+}
+
+struct Inner {
+    render: Histogram,
+    paint: Histogram,
+    dirty: Histogram,
+    missed_seconds: u64,
+    requests: BTreeMap<(String, String), u64>,
+}
+
+pub(crate) struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Metrics {
+        Metrics {
+            inner: Mutex::new(Inner {
+                render: Histogram::new(SECONDS),
+                paint: Histogram::new(SECONDS),
+                dirty: Histogram::new(BUCKETS),
+                missed_seconds: 0,
+                requests: Default::default(),
+            }),
+        }
+    }
+
+    pub(crate) fn render_time(&self, d: Duration) {
+        self.inner.lock_or_recover().render.observe(d.as_secs_f64());
+    }
+
+    pub(crate) fn paint_time(&self, d: Duration) {
+        self.inner.lock_or_recover().paint.observe(d.as_secs_f64());
+    }
+
+    #[cfg_attr(not(target_os = "illumos"), allow(unused))]
+    pub(crate) fn dirty_buckets(&self, n: usize) {
+        self.inner.lock_or_recover().dirty.observe(n as f64);
+    }
+
+    /*
+     * Record that, due to some delay, one or more seconds were never displayed
+     * on the clock.
+     */
+    pub(crate) fn missed_seconds(&self, n: u64) {
+        self.inner.lock_or_recover().missed_seconds += n;
+    }
+
+    pub(crate) fn request(&self, method: &str, path: &str) {
+        *self
+            .inner
+            .lock_or_recover()
+            .requests
+            .entry((method.to_string(), path.to_string()))
+            .or_default() += 1;
+    }
+
+    /*
+     * Render all metrics in the Prometheus text format.  The current display
+     * mode is not tracked here, so the caller provides it.
+     */
+    pub(crate) fn render(&self, mode: &str, modes: &[&str]) -> String {
+        let i = self.inner.lock_or_recover();
+        let mut out = String::new();
+
+        writeln!(
+            out,
+            "# HELP clock_render_seconds Time taken to render each frame.\n\
+            # TYPE clock_render_seconds histogram"
+        )
+        .unwrap();
+        i.render.render(&mut out, "clock_render_seconds", "");
+
+        writeln!(
+            out,
+            "# HELP clock_paint_seconds Time taken to send each frame to the \
+            display.\n\
+            # TYPE clock_paint_seconds histogram"
+        )
+        .unwrap();
+        i.paint.render(
+            &mut out,
+            "clock_paint_seconds",
+            &format!("backend=\"{BACKEND}\""),
+        );
+
+        if cfg!(target_os = "illumos") {
+            writeln!(
+                out,
+                "# HELP clock_dirty_buckets Framebuffer stripes written per \
+                frame.\n\
+                # TYPE clock_dirty_buckets histogram"
+            )
+            .unwrap();
+            i.dirty.render(&mut out, "clock_dirty_buckets", "");
+        }
+
+        writeln!(
+            out,
+            "# HELP clock_missed_seconds_total Seconds never displayed because \
+            a frame was late.\n\
+            # TYPE clock_missed_seconds_total counter\n\
+            clock_missed_seconds_total {}",
+            i.missed_seconds,
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "# HELP clock_http_requests_total HTTP requests, by endpoint.\n\
+            # TYPE clock_http_requests_total counter"
+        )
+        .unwrap();
+        for ((method, path), count) in i.requests.iter() {
+            writeln!(
+                out,
+                "clock_http_requests_total{{method=\"{method}\",\
+                path=\"{path}\"}} {count}"
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP clock_mode What is currently being displayed.\n\
+            # TYPE clock_mode gauge"
+        )
+        .unwrap();
+        for m in modes {
+            writeln!(
+                out,
+                "clock_mode{{mode=\"{m}\"}} {}",
+                if *m == mode { 1 } else { 0 }
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
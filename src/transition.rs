@@ -0,0 +1,297 @@
+/*
+ * Copyright 2024 Oxide Computer Company
+ */
+
+/*
+ * Rather than cut straight from one thing on the display to the next (e.g.,
+ * from the clock to a message), we may show a few frames in between: fading
+ * from one to the other, sliding the new one in from the right, or dissolving
+ * from one to the other a stripe at a time.
+ *
+ * The framebuffer is written in stripes, and a stripe is only written if it
+ * has changed.  Each frame of a transition is confined to the rows which differ
+ * between the old frame and the new one, so that it is no more expensive to
+ * paint than the new frame would have been; a dissolve replaces each stripe
+ * once, so that the whole transition costs no more than a single redraw.
+ */
+
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use image::RgbImage;
+
+use crate::{config, STRIPES};
+
+/*
+ * What is being shown on the display.  Content posted through the API is
+ * identified by the number it was given when it was posted, so that replacing
+ * one message with another counts as a change.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Scene {
+    Blank,
+    Refresh,
+    Clock,
+    Message(u64),
+    Image(u64),
+    Timer(u64),
+}
+
+pub(crate) struct Transition<'a> {
+    config: &'a config::Transition,
+    /*
+     * The last frame painted, before it was moved or dimmed:
+     */
+    last: Option<RgbImage>,
+    /*
+     * What the last frame showed, and the second of the clock it showed, if
+     * any:
+     */
+    scene: Option<(Scene, Option<i64>)>,
+    /*
+     * Whether the next frame to be painted shows something different:
+     */
+    pending: bool,
+    /*
+     * The in-between frame, kept to avoid allocating one for every frame:
+     */
+    tween: RgbImage,
+    /*
+     * For a dissolve, the point in the transition at which each stripe
+     * changes over:
+     */
+    order: Vec<f32>,
+}
+
+impl Transition<'_> {
+    pub(crate) fn new(config: &config::Transition) -> Transition {
+        Transition {
+            config,
+            last: None,
+            scene: None,
+            pending: false,
+            tween: RgbImage::new(1, 1),
+            order: Vec::new(),
+        }
+    }
+
+    pub(crate) fn duration(&self) -> Duration {
+        Duration::from_millis(self.config.ms.into())
+    }
+
+    /*
+     * Record what the next frame will show.  The clock, and any countdown,
+     * also changes with each second that passes; if the transition applies
+     * to those changes too, pass the second that the frame shows.
+     */
+    pub(crate) fn show(&mut self, scene: Scene, second: Option<i64>) {
+        let second = second.filter(|_| self.config.digits);
+        let next = Some((scene, second));
+        if self.scene != next {
+            self.pending = self.scene.is_some();
+            self.scene = next;
+        }
+    }
+
+    /*
+     * If the frame about to be painted shows something different from the
+     * last, begin a transition, returning the rows which differ between the
+     * two frames.
+     */
+    pub(crate) fn begin(&mut self, next: &RgbImage) -> Option<Range<u32>> {
+        if !std::mem::take(&mut self.pending) {
+            return None;
+        }
+        if self.config.kind == config::TransitionKind::Cut {
+            return None;
+        }
+        let last = self.last.as_ref()?;
+        if last.dimensions() != next.dimensions() {
+            return None;
+        }
+
+        let stride = next.width() as usize * 3;
+        let differ = |y: &usize| {
+            let r = y * stride..(y + 1) * stride;
+            last.as_raw()[r.clone()] != next.as_raw()[r]
+        };
+        let height = next.height() as usize;
+        let first = (0..height).find(differ)?;
+        let end = (0..height).rev().find(differ)? + 1;
+
+        if self.tween.dimensions() != next.dimensions() {
+            self.tween = next.clone();
+        }
+
+        if self.config.kind == config::TransitionKind::Dissolve {
+            self.order = random_order(STRIPES);
+        }
+
+        Some(first as u32..end as u32)
+    }
+
+    /*
+     * Make the frame at this point in the transition, between 0 and 1, in
+     * which only the rows in "rows" differ from the new frame.
+     */
+    pub(crate) fn tween(
+        &mut self,
+        next: &RgbImage,
+        rows: Range<u32>,
+        t: f32,
+    ) -> &mut RgbImage {
+        let Some(last) = self.last.as_ref() else {
+            self.tween.clone_from(next);
+            return &mut self.tween;
+        };
+
+        let width = next.width() as usize;
+        let stride = width * 3;
+        let r = rows.start as usize * stride..rows.end as usize * stride;
+        let last = last.as_raw();
+        let next = next.as_raw();
+        let out: &mut [u8] = &mut self.tween;
+
+        out[..r.start].copy_from_slice(&next[..r.start]);
+        out[r.end..].copy_from_slice(&next[r.end..]);
+
+        let ease = t * t * (3.0 - 2.0 * t);
+        match self.config.kind {
+            config::TransitionKind::Cut => {
+                out[r.clone()].copy_from_slice(&next[r]);
+            }
+            config::TransitionKind::Crossfade => {
+                let a = (ease * 256.0) as u32;
+                for ((o, &l), &n) in out[r.clone()]
+                    .iter_mut()
+                    .zip(&last[r.clone()])
+                    .zip(&next[r])
+                {
+                    *o = ((l as u32 * (256 - a) + n as u32 * a) >> 8) as u8;
+                }
+            }
+            config::TransitionKind::Slide => {
+                /*
+                 * The new frame pushes the old one off to the left:
+                 */
+                let off = ((width as f32 * ease) as usize).min(width) * 3;
+                for y in rows.start as usize..rows.end as usize {
+                    let row = y * stride..(y + 1) * stride;
+                    let (o, l, n) =
+                        (&mut out[row.clone()], &last[row.clone()], &next[row]);
+                    o[..stride - off].copy_from_slice(&l[off..]);
+                    o[stride - off..].copy_from_slice(&n[..off]);
+                }
+            }
+            config::TransitionKind::Dissolve => {
+                /*
+                 * Each stripe covers the same pixels as the corresponding
+                 * stripe of the framebuffer:
+                 */
+                let chsz = (next.len() / 3 / STRIPES) * 3;
+                for (i, &at) in self.order.iter().enumerate() {
+                    let s = i * chsz..((i + 1) * chsz).min(out.len());
+                    let src = if at < t { next } else { last };
+                    out[s.clone()].copy_from_slice(&src[s]);
+                }
+                let rest = STRIPES * chsz;
+                out[rest..].copy_from_slice(&next[rest..]);
+            }
+        }
+
+        &mut self.tween
+    }
+
+    /*
+     * Keep a copy of the frame about to be painted, from which to begin the
+     * next transition.  If only some rows of it have changed since the last
+     * frame, we need only copy those.
+     */
+    pub(crate) fn remember(
+        &mut self,
+        img: &RgbImage,
+        damage: Option<Range<u32>>,
+    ) {
+        if self.config.kind == config::TransitionKind::Cut {
+            return;
+        }
+
+        match (self.last.as_mut(), damage) {
+            (Some(last), Some(rows))
+                if last.dimensions() == img.dimensions() =>
+            {
+                let stride = img.width() as usize * 3;
+                let r =
+                    rows.start as usize * stride..rows.end as usize * stride;
+                let dst: &mut [u8] = last;
+                dst[r.clone()].copy_from_slice(&img.as_raw()[r]);
+            }
+            (Some(last), _) => last.clone_from(img),
+            (None, _) => self.last = Some(img.clone()),
+        }
+    }
+
+    /*
+     * Play the transition from the last frame to this one, if one is due,
+     * calling "paint" with each frame in between and the rows of it which may
+     * have changed, and returning whether there was a transition.  Progress is
+     * measured by the clock, rather than by counting frames, so that a slow
+     * display sees fewer frames rather than a longer transition.
+     */
+    pub(crate) fn play<F>(
+        &mut self,
+        next: &RgbImage,
+        frame_time: Duration,
+        mut paint: F,
+    ) -> bool
+    where
+        F: FnMut(&mut RgbImage, Range<u32>),
+    {
+        let Some(rows) = self.begin(next) else {
+            return false;
+        };
+
+        let start = Instant::now();
+        let duration = self.duration().as_secs_f32().max(f32::EPSILON);
+        loop {
+            let frame = Instant::now();
+            let t = start.elapsed().as_secs_f32() / duration;
+            if t >= 1.0 {
+                break;
+            }
+
+            paint(self.tween(next, rows.clone(), t), rows.clone());
+
+            std::thread::sleep(frame_time.saturating_sub(frame.elapsed()));
+        }
+
+        true
+    }
+}
+
+/*
+ * A point between 0 and 1 for each of "n" items, in no particular order.  This
+ * need not be unpredictable, only different each time.
+ */
+fn random_order(n: usize) -> Vec<f32> {
+    let mut x = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+
+    (0..n)
+        .map(|_| {
+            /*
+             * The "splitmix64" generator:
+             */
+            x = x.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^= z >> 31;
+            (z >> 40) as f32 / (1u64 << 24) as f32
+        })
+        .collect()
+}
@@ -2,7 +2,10 @@
  * Copyright 2024 Oxide Computer Company
  */
 
-use std::{io::IsTerminal, sync::Mutex};
+use std::{
+    io::IsTerminal,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
 
 use slog::{o, Drain, Logger};
 
@@ -33,3 +36,19 @@ pub fn make_log(name: &'static str) -> Logger {
         )
     }
 }
+
+pub trait MutexExt<T> {
+    /*
+     * Lock the mutex, even if a thread panicked while holding it.  This is
+     * appropriate for data (e.g., metrics, or the last frame drawn) that
+     * cannot be left in an inconsistent state by a panic, and which should
+     * not bring down the clock.
+     */
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> MutexExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -8,6 +8,7 @@ use x11rb::atom_manager;
 use x11rb::connection::Connection;
 use x11rb::image::Image;
 use x11rb::properties::WmSizeHints;
+use x11rb::protocol::dpms::{ConnectionExt as _, DPMSMode};
 use x11rb::protocol::xproto::*;
 use x11rb::protocol::Event;
 use x11rb::rust_connection::RustConnection;
@@ -37,6 +38,12 @@ pub struct App<'a> {
     buf: Image<'a>,
 
     keys: GetKeyboardMappingReply,
+
+    /*
+     * Whether DPMS was enabled before we turned the monitor off, so that it
+     * can be put back that way when we turn the monitor on again:
+     */
+    dpms_enabled: Option<bool>,
 }
 
 impl<'a> App<'a> {
@@ -150,6 +157,7 @@ impl<'a> App<'a> {
             buf,
             black,
             keys,
+            dpms_enabled: None,
         })
     }
 
@@ -218,7 +226,7 @@ impl<'a> App<'a> {
         self.h.try_into().unwrap()
     }
 
-    pub fn apply(&mut self, img: &RgbImage) {
+    pub fn apply(&mut self, img: &RgbImage) -> Result<()> {
         for x in 0..img.width().min(self.buf.width() as u32) {
             for y in 0..img.height().min(self.buf.height() as u32) {
                 let px = img.get_pixel(x, y);
@@ -229,7 +237,43 @@ impl<'a> App<'a> {
             }
         }
 
-        self.redraw().expect("redraw");
+        self.redraw()
+    }
+
+    /*
+     * Turn the monitor off, or on again, through the DPMS extension.  DPMS
+     * must be enabled to force the monitor off, but then its idle timeouts
+     * apply as well, so when we turn the monitor on again we put DPMS back the
+     * way it was.
+     */
+    pub fn set_dpms(&mut self, on: bool) -> Result<()> {
+        let info = self.conn.dpms_info()?.reply()?;
+
+        if on {
+            let off = info.state && info.power_level != DPMSMode::ON;
+
+            /*
+             * If the monitor is off but we do not know how DPMS was before
+             * (e.g., we have restarted since turning it off), assume that it
+             * was disabled: the clock receives no input, so the idle timeouts
+             * would soon turn the monitor off again.
+             */
+            let enabled = self.dpms_enabled.take().unwrap_or(!off);
+
+            if off {
+                self.conn.dpms_force_level(DPMSMode::ON)?;
+            }
+            if info.state && !enabled {
+                self.conn.dpms_disable()?;
+            }
+        } else {
+            self.dpms_enabled.get_or_insert(info.state);
+            self.conn.dpms_enable()?;
+            self.conn.dpms_force_level(DPMSMode::OFF)?;
+        }
+
+        self.conn.flush()?;
+        Ok(())
     }
 
     pub fn poll(&mut self) -> Result<()> {